@@ -0,0 +1,523 @@
+use crate::block::double_sha256;
+use crate::network::Network;
+use crate::script::ScriptType;
+
+const BASE58_ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const BECH32_CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+const BECH32_CONST: u32 = 1;
+const BECH32M_CONST: u32 = 0x2bc830a3;
+
+/// Address of a scriptpubkey on `network`, if the script type has one.
+///
+/// P2PKH and P2SH use Base58Check, witness programs use bech32 (v0) or
+/// bech32m (v1+). Bare keys, multisig and OP_RETURN outputs have no address.
+pub fn script_to_address(script: &[u8], network: Network) -> Option<String> {
+    match ScriptType::classify(script) {
+        ScriptType::P2pkh => Some(base58check_encode(network.p2pkh_prefix(), &script[3..23])),
+        ScriptType::P2sh => Some(base58check_encode(network.p2sh_prefix(), &script[2..22])),
+        _ => {
+            let (version, program) = witness_program(script)?;
+            segwit_encode(network.bech32_hrp(), version, program)
+        }
+    }
+}
+
+/// Splits a segwit scriptpubkey (`OP_n <2..40 byte push>`) into version and program.
+pub fn witness_program(script: &[u8]) -> Option<(u8, &[u8])> {
+    let (&version_op, rest) = script.split_first()?;
+    let version = match version_op {
+        0x00 => 0,
+        0x51..=0x60 => version_op - 0x50,
+        _ => return None,
+    };
+    let (&len, program) = rest.split_first()?;
+    if !(2..=40).contains(&len) || program.len() != len as usize {
+        return None;
+    }
+    // v0 only defines 20 and 32 byte programs
+    if version == 0 && len != 20 && len != 32 {
+        return None;
+    }
+    Some((version, program))
+}
+
+/// Decodes a P2PKH, P2SH or segwit address into the scriptpubkey it pays to.
+///
+/// The address must belong to `network`: Base58 version bytes are checked
+/// against the network's prefixes and bech32 addresses against its HRP.
+pub fn address_to_script(address: &str, network: Network) -> Result<Vec<u8>, String> {
+    let hrp_prefix = format!("{}1", network.bech32_hrp());
+    if address.len() > hrp_prefix.len() && address.to_lowercase().starts_with(&hrp_prefix) {
+        let (version, program) = segwit_decode(network.bech32_hrp(), address)?;
+        let mut script = vec![if version == 0 { 0x00 } else { 0x50 + version }];
+        script.push(program.len() as u8);
+        script.extend(program);
+        return Ok(script);
+    }
+    let lower = address.to_lowercase();
+    if ["bc1", "tb1", "bcrt1"].iter().any(|p| lower.starts_with(p)) {
+        return Err(format!("Address {} is not a {} address", address, network));
+    }
+
+    let (version, payload) = base58check_decode(address)?;
+    if payload.len() != 20 {
+        return Err(format!("Invalid Base58 payload length in {}", address));
+    }
+    if version == network.p2pkh_prefix() {
+        let mut script = vec![0x76, 0xa9, 0x14];
+        script.extend(payload);
+        script.extend([0x88, 0xac]);
+        Ok(script)
+    } else if version == network.p2sh_prefix() {
+        let mut script = vec![0xa9, 0x14];
+        script.extend(payload);
+        script.push(0x87);
+        Ok(script)
+    } else {
+        Err(format!(
+            "Address {} is not a {} address (version byte {:#04x})",
+            address, network, version
+        ))
+    }
+}
+
+/// Base58 encoding of `version || payload || checksum`.
+pub fn base58check_encode(version: u8, payload: &[u8]) -> String {
+    let mut data = vec![version];
+    data.extend(payload);
+    let checksum = double_sha256(&data);
+    data.extend(&checksum[..4]);
+
+    // Repeated division of the big-endian number by 58
+    let mut digits: Vec<u8> = Vec::new();
+    for &byte in &data {
+        let mut carry = byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+
+    // Every leading zero byte is written as '1'
+    let leading_zeros = data.iter().take_while(|&&b| b == 0).count();
+    std::iter::repeat_n(b'1', leading_zeros)
+        .chain(digits.iter().rev().map(|&d| BASE58_ALPHABET[d as usize]))
+        .map(char::from)
+        .collect()
+}
+
+/// Decodes a Base58Check string into its version byte and payload.
+pub fn base58check_decode(s: &str) -> Result<(u8, Vec<u8>), String> {
+    // Repeated multiplication by 58, little-endian bytes
+    let mut bytes: Vec<u8> = Vec::new();
+    for c in s.bytes() {
+        let value = BASE58_ALPHABET
+            .iter()
+            .position(|&a| a == c)
+            .ok_or_else(|| format!("Invalid Base58 character {:?} in {}", c as char, s))?;
+        let mut carry = value as u32;
+        for byte in bytes.iter_mut() {
+            carry += (*byte as u32) * 58;
+            *byte = (carry & 0xff) as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push((carry & 0xff) as u8);
+            carry >>= 8;
+        }
+    }
+    let leading_ones = s.bytes().take_while(|&c| c == b'1').count();
+    let mut data = vec![0u8; leading_ones];
+    data.extend(bytes.iter().rev());
+
+    if data.len() < 5 {
+        return Err(format!("Base58Check string too short: {}", s));
+    }
+    let (body, checksum) = data.split_at(data.len() - 4);
+    if double_sha256(body)[..4] != *checksum {
+        return Err(format!("Invalid Base58Check checksum in {}", s));
+    }
+    Ok((body[0], body[1..].to_vec()))
+}
+
+/// Encodes a witness program as bech32 (v0) or bech32m (v1+), per BIP173/BIP350.
+pub fn segwit_encode(hrp: &str, version: u8, program: &[u8]) -> Option<String> {
+    if version > 16 || program.len() < 2 || program.len() > 40 {
+        return None;
+    }
+    let mut data = vec![version];
+    data.extend(convert_bits(program, 8, 5, true)?);
+
+    let constant = if version == 0 {
+        BECH32_CONST
+    } else {
+        BECH32M_CONST
+    };
+    let checksum = bech32_checksum(hrp, &data, constant);
+
+    let mut address = format!("{}1", hrp);
+    for value in data.iter().chain(checksum.iter()) {
+        address.push(BECH32_CHARSET[*value as usize] as char);
+    }
+    Some(address)
+}
+
+/// Decodes a segwit address for `hrp` into witness version and program,
+/// enforcing bech32 for v0 and bech32m for v1+ (BIP350).
+pub fn segwit_decode(hrp: &str, address: &str) -> Result<(u8, Vec<u8>), String> {
+    if address.to_lowercase() != address && address.to_uppercase() != address {
+        return Err(format!("Mixed case in bech32 address {}", address));
+    }
+    let address = address.to_lowercase();
+    if address.len() > 90 {
+        return Err(format!("Bech32 address too long: {}", address));
+    }
+    let separator = address
+        .rfind('1')
+        .ok_or_else(|| format!("Missing bech32 separator in {}", address))?;
+    let (addr_hrp, rest) = (&address[..separator], &address[separator + 1..]);
+    if addr_hrp != hrp {
+        return Err(format!(
+            "Address {} does not use the {} prefix",
+            address, hrp
+        ));
+    }
+    if rest.len() < 7 {
+        return Err(format!("Bech32 data too short in {}", address));
+    }
+
+    let values = rest
+        .bytes()
+        .map(|c| {
+            BECH32_CHARSET
+                .iter()
+                .position(|&a| a == c)
+                .map(|v| v as u8)
+                .ok_or_else(|| format!("Invalid bech32 character {:?} in {}", c as char, address))
+        })
+        .collect::<Result<Vec<u8>, String>>()?;
+
+    let mut check = hrp_expand(hrp);
+    check.extend(&values);
+    let constant = bech32_polymod(&check);
+
+    let (data, _checksum) = values.split_at(values.len() - 6);
+    let version = data[0];
+    let expected = if version == 0 {
+        BECH32_CONST
+    } else {
+        BECH32M_CONST
+    };
+    if constant != expected {
+        return Err(format!("Invalid bech32 checksum in {}", address));
+    }
+    if version > 16 {
+        return Err(format!(
+            "Invalid witness version {} in {}",
+            version, address
+        ));
+    }
+
+    let program = convert_bits(&data[1..], 5, 8, false)
+        .ok_or_else(|| format!("Invalid witness program padding in {}", address))?;
+    if !(2..=40).contains(&program.len())
+        || (version == 0 && program.len() != 20 && program.len() != 32)
+    {
+        return Err(format!(
+            "Invalid witness program length {} in {}",
+            program.len(),
+            address
+        ));
+    }
+    Ok((version, program))
+}
+
+fn bech32_polymod(values: &[u8]) -> u32 {
+    const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut chk: u32 = 1;
+    for &value in values {
+        let top = chk >> 25;
+        chk = ((chk & 0x1ffffff) << 5) ^ value as u32;
+        for (i, gen) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= gen;
+            }
+        }
+    }
+    chk
+}
+
+fn hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut expanded: Vec<u8> = hrp.bytes().map(|b| b >> 5).collect();
+    expanded.push(0);
+    expanded.extend(hrp.bytes().map(|b| b & 31));
+    expanded
+}
+
+fn bech32_checksum(hrp: &str, data: &[u8], constant: u32) -> [u8; 6] {
+    let mut values = hrp_expand(hrp);
+    values.extend(data);
+    values.extend([0u8; 6]);
+    let polymod = bech32_polymod(&values) ^ constant;
+    let mut checksum = [0u8; 6];
+    for (i, c) in checksum.iter_mut().enumerate() {
+        *c = ((polymod >> (5 * (5 - i))) & 31) as u8;
+    }
+    checksum
+}
+
+/// Regroups a bit stream from `from`-bit to `to`-bit values.
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+    let mut acc: u32 = 0;
+    let mut bits: u32 = 0;
+    let max = (1u32 << to) - 1;
+    let mut result = Vec::new();
+    for &value in data {
+        if (value as u32) >> from != 0 {
+            return None;
+        }
+        acc = (acc << from) | value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            result.push(((acc >> bits) & max) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            result.push(((acc << (to - bits)) & max) as u8);
+        }
+    } else if bits >= from || ((acc << (to - bits)) & max) != 0 {
+        return None;
+    }
+    Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script_hex(version: u8, program: &[u8]) -> String {
+        let op = if version == 0 { 0x00 } else { 0x50 + version };
+        let mut script = vec![op, program.len() as u8];
+        script.extend(program);
+        hex::encode(script)
+    }
+
+    /// Bech32 string for `hrp` over raw 5-bit `data`, checksummed with
+    /// `constant`, for building addresses that are wrong in one way only.
+    fn raw_bech32(hrp: &str, data: &[u8], constant: u32) -> String {
+        let checksum = bech32_checksum(hrp, data, constant);
+        let mut address = format!("{}1", hrp);
+        for value in data.iter().chain(checksum.iter()) {
+            address.push(BECH32_CHARSET[*value as usize] as char);
+        }
+        address
+    }
+
+    #[test]
+    fn base58check_vectors() {
+        for (address, version, payload) in [
+            (
+                "1BoatSLRHtKNngkdXEeobR76b53LETtpyT",
+                0x00,
+                "7680adec8eabcabac676be9e83854ade0bd22cdb",
+            ),
+            (
+                "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa",
+                0x00,
+                "62e907b15cbf27d5425399ebf6f0fb50ebb88f18",
+            ),
+            (
+                "3J98t1WpEZ73CNmQviecrnyiWrnqRhWNLy",
+                0x05,
+                "b472a266d0bd89c13706a4132ccfb16f7c3b9fcb",
+            ),
+            (
+                "1111111111111111111114oLvT2",
+                0x00,
+                "0000000000000000000000000000000000000000",
+            ),
+        ] {
+            let payload = hex::decode(payload).unwrap();
+            assert_eq!(base58check_encode(version, &payload), address);
+            assert_eq!(base58check_decode(address), Ok((version, payload)));
+        }
+    }
+
+    #[test]
+    fn base58check_rejects_bad_strings() {
+        for (address, error) in [
+            // Last character changed
+            (
+                "1BoatSLRHtKNngkdXEeobR76b53LETtpyU",
+                "Invalid Base58Check checksum",
+            ),
+            // 0, O, I and l aren't in the alphabet
+            (
+                "1BoatSLRHtKNngkdXEeobR76b53LETtpy0",
+                "Invalid Base58 character",
+            ),
+            (
+                "1BoatSLRHtKNngkdXEeobR76b53LETtpyO",
+                "Invalid Base58 character",
+            ),
+            (
+                "1BoatSLRHtKNngkdXEeobR76b53LETtpyI",
+                "Invalid Base58 character",
+            ),
+            (
+                "1BoatSLRHtKNngkdXEeobR76b53LETtpyl",
+                "Invalid Base58 character",
+            ),
+            ("1111", "Base58Check string too short"),
+        ] {
+            let result = base58check_decode(address);
+            assert!(result.unwrap_err().starts_with(error), "{}", address);
+        }
+    }
+
+    #[test]
+    fn bech32_and_bech32m_vectors() {
+        // BIP173 (v0, bech32) and BIP350 (v1+, bech32m)
+        for (address, script) in [
+            ("BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4", "0014751e76e8199196d454941c45d1b3a323f1433bd6"),
+            (
+                "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7",
+                "00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262",
+            ),
+            (
+                "tb1qqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesrxh6hy",
+                "0020000000c4a5cad46221b2a187905e5266362b99d5e91c6ce24d165dab93e86433",
+            ),
+            (
+                "bc1pw508d6qejxtdg4y5r3zarvary0c5xw7kw508d6qejxtdg4y5r3zarvary0c5xw7kt5nd6y",
+                "5128751e76e8199196d454941c45d1b3a323f1433bd6751e76e8199196d454941c45d1b3a323f1433bd6",
+            ),
+            ("BC1SW50QGDZ25J", "6002751e"),
+            ("bc1zw508d6qejxtdg4y5r3zarvaryvaxxpcs", "5210751e76e8199196d454941c45d1b3a323"),
+            (
+                "tb1pqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesf3hn0c",
+                "5120000000c4a5cad46221b2a187905e5266362b99d5e91c6ce24d165dab93e86433",
+            ),
+            (
+                "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0",
+                "512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            ),
+        ] {
+            let hrp = &address[..address.rfind('1').unwrap()].to_lowercase();
+            let (version, program) = segwit_decode(hrp, address).unwrap();
+            assert_eq!(script_hex(version, &program), script, "{}", address);
+            assert_eq!(
+                segwit_encode(hrp, version, &program),
+                Some(address.to_lowercase())
+            );
+        }
+    }
+
+    #[test]
+    fn bech32_rejects_bad_addresses() {
+        let program = [0x75u8; 20];
+        let mut v0 = vec![0u8];
+        v0.extend(convert_bits(&program, 8, 5, true).unwrap());
+        let mut v1 = vec![1u8];
+        v1.extend(convert_bits(&[0x75u8; 32], 8, 5, true).unwrap());
+        // 32 bytes take 52 five-bit values, the last with 4 bits of padding,
+        // which must be zero. 20 bytes fill 32 values exactly, so one more
+        // value is 5 bits of padding, more than a whole-byte program allows.
+        let mut nonzero_padding = vec![0u8];
+        nonzero_padding.extend(convert_bits(&[0x75u8; 32], 8, 5, true).unwrap());
+        *nonzero_padding.last_mut().unwrap() |= 1;
+        let mut long_padding = v0.clone();
+        long_padding.push(0);
+        let mut v0_16_bytes = vec![0u8];
+        v0_16_bytes.extend(convert_bits(&[0x75u8; 16], 8, 5, true).unwrap());
+        let mut v1_1_byte = vec![1u8];
+        v1_1_byte.extend(convert_bits(&[0x75u8; 1], 8, 5, true).unwrap());
+        let mut v1_41_bytes = vec![1u8];
+        v1_41_bytes.extend(convert_bits(&[0x75u8; 41], 8, 5, true).unwrap());
+        let mut v17 = vec![17u8];
+        v17.extend(convert_bits(&program, 8, 5, true).unwrap());
+
+        let valid = raw_bech32("bc", &v0, BECH32_CONST);
+        let mixed_case = format!("{}{}", &valid[..10], valid[10..].to_uppercase());
+
+        for (address, error) in [
+            (mixed_case, "Mixed case"),
+            (
+                raw_bech32("bc", &v0, BECH32M_CONST),
+                "Invalid bech32 checksum",
+            ),
+            (
+                raw_bech32("bc", &v1, BECH32_CONST),
+                "Invalid bech32 checksum",
+            ),
+            (raw_bech32("tb", &v0, BECH32_CONST), "Address"),
+            (
+                raw_bech32("bc", &nonzero_padding, BECH32_CONST),
+                "Invalid witness program padding",
+            ),
+            (
+                raw_bech32("bc", &long_padding, BECH32_CONST),
+                "Invalid witness program padding",
+            ),
+            (
+                raw_bech32("bc", &v0_16_bytes, BECH32_CONST),
+                "Invalid witness program length 16",
+            ),
+            (
+                raw_bech32("bc", &v1_1_byte, BECH32M_CONST),
+                "Invalid witness program length 1",
+            ),
+            (
+                raw_bech32("bc", &v1_41_bytes, BECH32M_CONST),
+                "Invalid witness program length 41",
+            ),
+            (
+                raw_bech32("bc", &v17, BECH32M_CONST),
+                "Invalid witness version 17",
+            ),
+            (
+                format!("{}o", &valid[..valid.len() - 1]),
+                "Invalid bech32 character",
+            ),
+        ] {
+            let result = segwit_decode("bc", &address);
+            assert!(result.unwrap_err().starts_with(error), "{}", address);
+        }
+        assert_eq!(segwit_decode("bc", &valid), Ok((0, program.to_vec())));
+    }
+
+    #[test]
+    fn bip350_invalid_vectors() {
+        for (address, error) in [
+            // v1 with a bech32 checksum, v0 with a bech32m one
+            (
+                "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqh2y7hd",
+                "Invalid bech32 checksum",
+            ),
+            (
+                "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kemeawh",
+                "Invalid bech32 checksum",
+            ),
+            ("bc1pw5dgrnzv", "Invalid witness program length 1"),
+            (
+                "BC1QR508D6QEJXTDG4Y5R3ZARVARYV98GJ9P",
+                "Invalid witness program length 16",
+            ),
+            (
+                "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7v07qwwzcrf",
+                "Invalid witness program padding",
+            ),
+        ] {
+            let result = segwit_decode("bc", address);
+            assert!(result.unwrap_err().starts_with(error), "{}", address);
+        }
+    }
+}
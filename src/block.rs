@@ -1,11 +1,22 @@
-use crate::tx::{Output, Transaction};
+use crate::coinbase::increment_extranonce;
+use crate::consensus::{BIP320_VERSION_MASK, MAX_FUTURE_BLOCK_TIME, MEDIAN_TIME_SPAN};
+use crate::merkle::{id_to_hash, MerkleProof, MerkleTree};
+use crate::network::Network;
+use crate::pow::{bits_to_target, target_to_bytes};
+use crate::tx::{ByteReader, Transaction};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
-use std::{fs::File, io::Write, time::SystemTime, vec};
-
-const WITNESS_RESERVED_VALUE: [u8; 32] = [0; 32];
-
-#[derive(Serialize, Deserialize, Debug)]
+use sha2::digest::generic_array::GenericArray;
+use sha2::{compress256, Digest, Sha256};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use std::{fs, fs::File, io::Write, vec};
+
+pub const WITNESS_RESERVED_VALUE: [u8; 32] = [0; 32];
+pub const WITNESS_COMMITMENT_HEADER: [u8; 4] = [0xaa, 0x21, 0xa9, 0xed];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BlockHeader {
     pub version: u32,
     pub previous_block_hash: String,
@@ -15,114 +26,211 @@ pub struct BlockHeader {
     pub nonce: u32,
 }
 
+impl BlockHeader {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut header_bin = vec![];
+        header_bin.extend(&self.version.to_le_bytes()); // Little endian for version
+        header_bin.extend(&hex::decode(&self.previous_block_hash).unwrap()); // Hex-decoded previous block hash
+        header_bin.extend(&hex::decode(&self.merkle_root).unwrap()); // Hex-decoded merkle root
+        header_bin.extend(&self.time.to_le_bytes()); // Little endian for time
+        header_bin.extend(&self.bits.to_le_bytes()); // Little endian for bits
+        header_bin.extend(&self.nonce.to_le_bytes()); // Little endian for nonce
+        header_bin
+    }
+
+    /// Reads an 80 byte header. Hashes are kept in serialized byte order, as in `serialize`.
+    pub fn parse(reader: &mut ByteReader) -> Result<BlockHeader, String> {
+        Ok(BlockHeader {
+            version: reader.read_u32()?,
+            previous_block_hash: hex::encode(reader.read_bytes(32)?),
+            merkle_root: hex::encode(reader.read_bytes(32)?),
+            time: reader.read_u32()?,
+            bits: reader.read_u32()?,
+            nonce: reader.read_u32()?,
+        })
+    }
+
+    /// Block hash in the usual (reversed) display order.
+    pub fn hash(&self) -> String {
+        let mut hash = double_sha256(&self.serialize());
+        hash.reverse();
+        hex::encode(hash)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Block {
     pub header: BlockHeader,
     pub transactions: Vec<Transaction>,
     pub txids: Vec<String>,
+    /// Merkle branch of the coinbase (position 0), so the root can be
+    /// updated from a new coinbase txid without rehashing every txid.
+    pub coinbase_branch: MerkleProof,
 }
 
 impl Block {
-    pub fn mine(&mut self, difficulty_target: &str) {
-        // Set up the header with valid values
-        self.header.version = 4; // Ensure version is at least 4.
-        self.header.bits = 0x1f00ffff; // Set bits to specified value.
-        self.header.time = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as u32; // Set time to current timestamp.
-        self.header.merkle_root = self.generate_merkle_root().unwrap(); // Compute merkle root based on transactions.
-        self.header.previous_block_hash =
-            "0000000000000000000000000000000000000000000000000000000000000000".to_owned();
+    /// Searches for a header hash that meets the target encoded in
+    /// `header.bits`. The caller sets the bits and the base `header.version`
+    /// (normally `VERSIONBITS_TOP_BITS`, which BIP320 rolling assumes). The
+    /// nonce is searched on `job.threads` threads; when all 2^32 values fail,
+    /// the next version in `job.version_mask` is tried, then the next second
+    /// up to `job.max_time_offset` past the start, and finally the coinbase
+    /// extranonce is bumped (recomputing the merkle root) and time and version
+    /// start over.
+    pub fn mine(&mut self, job: &MiningJob) -> Result<MiningStats, String> {
+        let target = target_to_bytes(&bits_to_target(self.header.bits)?);
+        if job.version_mask & !BIP320_VERSION_MASK != 0 {
+            return Err(format!(
+                "Version mask {:08x} is outside the BIP320 mask {:08x}",
+                job.version_mask, BIP320_VERSION_MASK
+            ));
+        }
 
-        let difficulty_bytes =
-            hex::decode(difficulty_target).expect("Invalid hex in difficulty target");
+        // The time must be past the median time past and at most 2 hours ahead
+        let now = unix_time();
+        // A clock that ticked past the time limit since the bits were picked
+        // is fine: the header may be behind it
+        let start_time = now
+            .min(job.time_limit.unwrap_or(u32::MAX))
+            .max(job.min_time);
+        let max_offset = job.max_time_offset.min(MAX_FUTURE_BLOCK_TIME);
+        let max_time = now + max_offset;
+        if start_time > max_time {
+            return Err(format!(
+                "Earliest allowed time {} is more than {} seconds in the future",
+                start_time, max_offset
+            ));
+        }
+        let max_time = match job.time_limit {
+            Some(limit) if limit < start_time => {
+                return Err(format!(
+                    "Earliest allowed time {} is past {}, the last time the bits {:08x} are valid",
+                    start_time, limit, self.header.bits
+                ))
+            }
+            Some(limit) => max_time.min(limit),
+            None => max_time,
+        };
+
+        self.header.time = start_time;
+        self.header.merkle_root = self.generate_merkle_root().unwrap(); // Compute merkle root based on transactions.
 
+        let base_version = self.header.version & !job.version_mask;
+        let mut rolled_bits = 0;
+        let start = Instant::now();
+        let mut hashes = 0;
         loop {
-            let header_bin = self.serialize_header();
-            let hash = double_sha256(&header_bin);
-            let reversed_hash = hash.iter().rev().copied().collect::<Vec<u8>>();
+            let (nonce, tried) = search_nonces(&self.header.serialize(), &target, job.threads);
+            hashes += tried;
+            if let Some(nonce) = nonce {
+                self.header.nonce = nonce;
+                return Ok(MiningStats {
+                    hashes,
+                    elapsed: start.elapsed(),
+                });
+            }
 
-            if reversed_hash < difficulty_bytes {
-                // println!("Block mined with hash: {:x}", hex::encode(reversed_hash));
-                break;
+            // Count through every combination of the mask bits, back to 0 when done
+            rolled_bits = (rolled_bits | !job.version_mask).wrapping_add(1) & job.version_mask;
+            self.header.version = base_version | rolled_bits;
+            if rolled_bits != 0 {
+                continue;
+            }
+            if self.header.time < max_time {
+                self.header.time += 1;
+                continue;
             }
-            self.header.nonce += 1;
+            self.header.time = start_time;
+            self.roll_extranonce()?;
         }
     }
 
-    fn serialize_header(&self) -> Vec<u8> {
-        let mut header_bin = vec![];
-        header_bin.extend(&self.header.version.to_le_bytes()); // Little endian for version
-        header_bin.extend(&hex::decode(&self.header.previous_block_hash).unwrap()); // Hex-decoded previous block hash
-        header_bin.extend(&hex::decode(&self.header.merkle_root).unwrap()); // Hex-decoded merkle root
-        header_bin.extend(&self.header.time.to_le_bytes()); // Little endian for time
-        header_bin.extend(&self.header.bits.to_le_bytes()); // Little endian for bits
-        header_bin.extend(&self.header.nonce.to_le_bytes()); // Little endian for nonce
-        header_bin
+    /// Gives the coinbase a new extranonce and updates its txid and the merkle root.
+    fn roll_extranonce(&mut self) -> Result<(), String> {
+        let coinbase = self
+            .transactions
+            .first_mut()
+            .ok_or("Block has no coinbase")?;
+        increment_extranonce(coinbase)?;
+        self.txids[0] = coinbase.calculate_txid()?;
+        let leaf = id_to_hash(&self.txids[0])?;
+        self.header.merkle_root = hex::encode(self.coinbase_branch.compute_root(leaf));
+        Ok(())
     }
 
-    /// Generates the Merkle root from the block's transactions.
-    fn generate_merkle_root(&mut self) -> Option<String> {
-        self.compute_txids();
-
-        if self.txids.is_empty() {
-            return None;
+    /// Parses a serialized block: header, transaction count and every
+    /// transaction, which must use up all of `data`.
+    pub fn parse(data: &[u8], network: Network) -> Result<Block, String> {
+        let mut reader = ByteReader::new(data);
+        let header = BlockHeader::parse(&mut reader)?;
+        let count = reader.read_varint()?;
+        let mut transactions = Vec::new();
+        for i in 0..count {
+            let tx = Transaction::parse(&mut reader, network)
+                .map_err(|e| format!("Transaction {}: {}", i, e))?;
+            transactions.push(tx);
+        }
+        if !reader.is_empty() {
+            return Err("Trailing data after the last transaction".to_string());
         }
 
-        let mut level = self
-            .txids
-            .iter()
-            .map(|txid| {
-                hex::decode(txid)
-                    .unwrap()
-                    .into_iter()
-                    .rev()
-                    .collect::<Vec<u8>>()
-            })
-            .collect::<Vec<_>>();
-
-        while level.len() > 1 {
-            let mut next_level = Vec::new();
-
-            for chunk in level.chunks(2) {
-                let pair_hash = match chunk {
-                    [left] => double_sha256(&[left.clone(), left.clone()].concat()),
-                    [left, right] => double_sha256(&[left.clone(), right.clone()].concat()),
-                    _ => unreachable!(),
-                };
-                next_level.push(pair_hash);
-            }
+        let mut block = Block {
+            header,
+            transactions,
+            txids: vec![],
+            coinbase_branch: MerkleProof::default(),
+        };
+        block.compute_txids();
+        Ok(block)
+    }
 
-            level = next_level;
+    /// Parses the blocks in a `blk*.dat` file, each framed by the network
+    /// magic and its length. Zero bytes where a frame would start are the
+    /// file's preallocated tail and end the file.
+    pub fn parse_blk_file(data: &[u8], network: Network) -> Result<Vec<Block>, String> {
+        let mut reader = ByteReader::new(data);
+        let mut blocks = Vec::new();
+        while reader.peek(4).is_some_and(|magic| magic != [0; 4]) {
+            let magic = reader.read_bytes(4)?;
+            if magic != network.magic() {
+                return Err(format!(
+                    "Block {}: magic {} is not {}'s {}",
+                    blocks.len(),
+                    hex::encode(magic),
+                    network,
+                    hex::encode(network.magic())
+                ));
+            }
+            let len = reader.read_u32()? as usize;
+            let block = Block::parse(reader.read_bytes(len)?, network)
+                .map_err(|e| format!("Block {}: {}", blocks.len(), e))?;
+            blocks.push(block);
         }
+        Ok(blocks)
+    }
 
-        if let Some(first) = level.get(0) {
-            // Now `first` is a reference to the first element (&Vec<u8>), not the owned Vec<u8>.
-            Some(hex::encode(first))
-        } else {
-            None
-        }
+    /// Generates the Merkle root from the block's transactions.
+    /// Also records the coinbase branch for later extranonce changes.
+    fn generate_merkle_root(&mut self) -> Option<String> {
+        self.compute_txids();
+        let tree = MerkleTree::from_ids(&self.txids).ok()?;
+        self.coinbase_branch = tree.proof(0)?;
+        tree.root_hex()
     }
 
     pub fn generate_output(&self) {
         let mut output = File::create("output.txt").expect("Failed to create output.txt");
 
         // Convert binary header to hexadecimal string
-        let header_hex = hex::encode(self.serialize_header());
+        let header_hex = hex::encode(self.header.serialize());
 
         // Write block header in hex format
         writeln!(output, "{}", header_hex).expect("Failed to write header to file");
 
-        // Check if we have at least one transaction and the first is a coinbase transaction
-        if let Some(coinbase_tx) = self.transactions.get(0) {
-            let witness_commitment = self
-                .calculate_witness_commitment()
-                .expect("Failed to calculate witness commitment");
-            let updated_coinbase_tx =
-                self.create_coinbase_transaction(coinbase_tx, &witness_commitment);
-            let coinbase_tx_hex =
-                hex::encode(Block::serialize_transaction(&updated_coinbase_tx, true));
+        // The coinbase already carries the witness commitment from template assembly,
+        // so this is exactly the transaction whose txid is in the merkle root
+        if let Some(coinbase_tx) = self.transactions.first() {
+            let coinbase_tx_hex = hex::encode(Block::serialize_transaction(coinbase_tx));
             writeln!(output, "{}", coinbase_tx_hex)
                 .expect("Failed to write coinbase transaction to file");
         }
@@ -141,34 +249,37 @@ impl Block {
         }
     }
 
-    // 7533d87ec9e2f0eda1298c2e2e37141c275358c4884fd90fbb0f87d67e5f0ce0
-
-    // Helper to create an updated coinbase transaction with witness commitment
-    fn create_coinbase_transaction(
-        &self,
-        existing_coinbase: &Transaction,
-        witness_commitment: &str,
-    ) -> Transaction {
-        let mut new_coinbase = existing_coinbase.clone();
-        // Modify the coinbase transaction to include the witness commitment
-        new_coinbase.vout.push(Output {
-            scriptpubkey: format!("6a24aa21a9ed{}", witness_commitment),
-            scriptpubkey_asm: format!("OP_RETURN {}", witness_commitment),
-            scriptpubkey_type: String::from("nulldata"),
-            scriptpubkey_address: None,
-            value: 0,
-        });
-        new_coinbase
+    /// Serialized block: header, transaction count and every transaction in
+    /// witness serialization, as a node would accept it from `submitblock`.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = self.header.serialize();
+        data.extend(Self::serialize_varint(self.transactions.len() as u64));
+        for tx in &self.transactions {
+            data.extend(Self::serialize_transaction(tx));
+        }
+        data
     }
 
-    fn serialize_transaction(tx: &Transaction, is_coinbase: bool) -> Vec<u8> {
+    /// Writes the serialized block to `path`: raw bytes for a `.dat` file,
+    /// otherwise a single line of hex.
+    pub fn write_raw(&self, path: &Path) -> Result<(), String> {
+        let data = self.serialize();
+        let contents = if path.extension().is_some_and(|ext| ext == "dat") {
+            data
+        } else {
+            format!("{}\n", hex::encode(data)).into_bytes()
+        };
+        fs::write(path, contents).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    pub fn serialize_transaction(tx: &Transaction) -> Vec<u8> {
         let mut data = Vec::new();
 
         // Transaction version
         data.extend(&tx.version.to_le_bytes());
 
         // Marker and flag for SegWit transactions
-        if is_coinbase || tx.vin.iter().any(|input| input.witness.is_some()) {
+        if tx.has_witness() {
             data.push(0x00); // Marker
             data.push(0x01); // Flag
         }
@@ -177,20 +288,16 @@ impl Block {
         data.extend(Self::serialize_varint(tx.vin.len() as u64));
 
         // Inputs
-        for (i, input) in tx.vin.iter().enumerate() {
-            // Previous TXID (32 bytes, reversed for regular, not altered for coinbase)
+        for input in &tx.vin {
+            // Previous TXID (32 bytes, reversed)
             let prev_txid = hex::decode(&input.txid).expect("Invalid previous TXID");
-            if i == 0 && is_coinbase {
-                data.extend(&prev_txid);
-            } else {
-                data.extend(prev_txid.iter().rev());
-            }
+            data.extend(prev_txid.iter().rev());
 
             // Previous output index (4 bytes)
             data.extend(&input.vout.to_le_bytes());
 
             // ScriptSig (varint followed by the actual script)
-            let scriptsig_bytes = input.scriptsig.as_bytes();
+            let scriptsig_bytes = hex::decode(&input.scriptsig).expect("Invalid scriptsig");
             data.extend(Self::serialize_varint(scriptsig_bytes.len() as u64));
             data.extend(scriptsig_bytes);
 
@@ -212,14 +319,9 @@ impl Block {
         }
 
         // Witnesses (only if the flag is set)
-        if is_coinbase || tx.vin.iter().any(|input| input.witness.is_some()) {
-            for (i, input) in tx.vin.iter().enumerate() {
-                if i == 0 && is_coinbase {
-                    // Handle coinbase transaction's witness reserved value
-                    data.extend(Self::serialize_varint(1)); // One element in witness stack
-                    data.extend(Self::serialize_varint(WITNESS_RESERVED_VALUE.len() as u64));
-                    data.extend(&WITNESS_RESERVED_VALUE);
-                } else if let Some(witness) = &input.witness {
+        if tx.has_witness() {
+            for input in &tx.vin {
+                if let Some(witness) = &input.witness {
                     // Witness stack size
                     data.extend(Self::serialize_varint(witness.len() as u64));
                     // Witness data
@@ -276,79 +378,182 @@ impl Block {
                 .collect();
         }
     }
+}
 
-    pub fn calculate_witness_commitment(&self) -> Result<String, String> {
-        let wtxids = self.compute_wtxids()?;
-        let witness_root = self.generate_merkle_root_wtxids(&wtxids)?;
-        let witness_reserved_value = hex::encode(WITNESS_RESERVED_VALUE);
-        let commitment_input = witness_root + &witness_reserved_value;
-        let commitment = Self::hash256(&commitment_input);
-        Ok(commitment)
-    }
+/// Search space for one `Block::mine` call besides the 32-bit nonce.
+pub struct MiningJob {
+    pub threads: usize,
+    /// Version bits that may be rolled, within `BIP320_VERSION_MASK`; 0 disables.
+    pub version_mask: u32,
+    /// Earliest allowed timestamp, i.e. the median time past plus one.
+    pub min_time: u32,
+    /// How many seconds past the current time nTime may be rolled, capped at
+    /// `MAX_FUTURE_BLOCK_TIME`; 0 disables.
+    pub max_time_offset: u32,
+    /// Latest timestamp the header's bits stay valid for, if later ones
+    /// would change the required bits (testnet's 20-minute rule).
+    pub time_limit: Option<u32>,
+}
+
+/// Hashes tried and time taken by `Block::mine`.
+pub struct MiningStats {
+    pub hashes: u64,
+    pub elapsed: Duration,
+}
 
-    fn hash256(input: &str) -> String {
-        let decoded_input = hex::decode(input).expect("Failed to decode input as hex");
-        let h1 = Sha256::digest(&decoded_input);
-        let h2 = Sha256::digest(&h1);
-        hex::encode(h2)
+impl MiningStats {
+    /// Hashes per second.
+    pub fn hash_rate(&self) -> f64 {
+        self.hashes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
     }
+}
 
-    // Helper function to generate the Merkle root from wtxids
-    fn generate_merkle_root_wtxids(&self, wtxids: &[String]) -> Result<String, String> {
-        let mut level = wtxids
-            .iter()
-            .map(|id| {
-                let mut buf = hex::decode(id).unwrap();
-                buf.reverse();
-                buf
-            })
-            .collect::<Vec<_>>();
-
-        while level.len() > 1 {
-            let mut next_level = Vec::new();
-            for chunk in level.chunks(2) {
-                let left = &chunk[0];
-                let right = chunk.get(1).unwrap_or(left);
-                let combined = [left.as_slice(), right.as_slice()].concat();
-                let hash = double_sha256(&combined);
-                next_level.push(hash);
-            }
-            level = next_level;
+/// How often workers check whether another thread already found a nonce.
+const FOUND_CHECK_INTERVAL: u64 = 4096;
+
+/// Tries every nonce for `header` (80 bytes), thread `t` taking the nonces
+/// congruent to `t` mod `threads`. Returns the lowest winning nonce any thread
+/// found, if one did, and the number of hashes computed.
+fn search_nonces(header: &[u8], target: &[u8; 32], threads: usize) -> (Option<u32>, u64) {
+    let found = AtomicU64::new(u64::MAX);
+    let hashes = AtomicU64::new(0);
+
+    thread::scope(|s| {
+        for t in 0..threads {
+            let (found, hashes) = (&found, &hashes);
+            s.spawn(move || {
+                let mut hasher = HeaderHasher::new(header);
+                let mut count = 0u64;
+                let mut nonce = t as u64;
+                while nonce <= u32::MAX as u64 {
+                    if count.is_multiple_of(FOUND_CHECK_INTERVAL)
+                        && found.load(Ordering::Relaxed) != u64::MAX
+                    {
+                        break;
+                    }
+                    let hash = hasher.hash(nonce as u32);
+                    count += 1;
+                    // The hash is compared as a little-endian number
+                    if hash.iter().rev().le(target.iter()) {
+                        found.fetch_min(nonce, Ordering::Relaxed);
+                        break;
+                    }
+                    nonce += threads as u64;
+                }
+                hashes.fetch_add(count, Ordering::Relaxed);
+            });
         }
-        level
-            .first()
-            .map(|hash| hex::encode(hash))
-            .ok_or_else(|| "Failed to generate Merkle root.".to_string())
+    });
+
+    let nonce = found.into_inner();
+    (
+        (nonce != u64::MAX).then_some(nonce as u32),
+        hashes.into_inner(),
+    )
+}
+
+const SHA256_INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// Double SHA-256 of an 80 byte header where only the nonce changes. The
+/// first 64 bytes are compressed once up front (the midstate), so each nonce
+/// costs one compression for the padded header tail and one for the second
+/// hash, with no serialization or allocation.
+struct HeaderHasher {
+    midstate: [u32; 8],
+    /// Bytes 64..80 of the header followed by SHA-256 padding for 80 bytes.
+    tail: [u8; 64],
+}
+
+impl HeaderHasher {
+    fn new(header: &[u8]) -> Self {
+        let mut midstate = SHA256_INITIAL_STATE;
+        compress256(
+            &mut midstate,
+            &[GenericArray::clone_from_slice(&header[..64])],
+        );
+
+        let mut tail = [0u8; 64];
+        tail[..16].copy_from_slice(&header[64..80]);
+        tail[16] = 0x80;
+        tail[56..].copy_from_slice(&(80u64 * 8).to_be_bytes());
+        HeaderHasher { midstate, tail }
     }
 
-    pub fn compute_wtxids(&self) -> Result<Vec<String>, String> {
-        let mut wtxids = Vec::new();
-        for (i, tx) in self.transactions.iter().enumerate() {
-            if i == 0 && tx.is_coinbase() {
-                // For coinbase transactions, use a special wtxid of all zeros
-                wtxids.push(
-                    "0000000000000000000000000000000000000000000000000000000000000000".to_string(),
-                );
-            } else {
-                // Check if the transaction has witness data
-                let has_witness = tx.vin.iter().any(|input| input.witness.is_some());
-                if has_witness {
-                    // For transactions with witness data, compute the wtxid
-                    let wtxid = tx.calculate_wtxid()?;
-                    wtxids.push(wtxid);
-                } else {
-                    println!("?? {:?}", self.txids[i].clone());
-                    // For transactions without witness data, use the txid
-                    wtxids.push(self.txids[i].clone());
-                }
-            }
+    fn hash(&mut self, nonce: u32) -> [u8; 32] {
+        self.tail[12..16].copy_from_slice(&nonce.to_le_bytes());
+        let mut state = self.midstate;
+        compress256(&mut state, &[GenericArray::clone_from_slice(&self.tail)]);
+
+        // Second hash over the 32 byte first hash, padded the same way
+        let mut block = [0u8; 64];
+        for (chunk, word) in block.chunks_exact_mut(4).zip(state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        block[32] = 0x80;
+        block[56..].copy_from_slice(&(32u64 * 8).to_be_bytes());
+        let mut state = SHA256_INITIAL_STATE;
+        compress256(&mut state, &[GenericArray::clone_from_slice(&block)]);
+
+        let mut hash = [0u8; 32];
+        for (chunk, word) in hash.chunks_exact_mut(4).zip(state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
         }
-        Ok(wtxids)
+        hash
     }
 }
 
+pub fn unix_time() -> u32 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32
+}
+
+/// Median timestamp of the last `MEDIAN_TIME_SPAN` headers (BIP113), which a
+/// new block's time must exceed. 0 for an empty chain.
+pub fn median_time_past(headers: &[BlockHeader]) -> u32 {
+    let mut times = headers
+        .iter()
+        .rev()
+        .take(MEDIAN_TIME_SPAN)
+        .map(|header| header.time)
+        .collect::<Vec<_>>();
+    times.sort_unstable();
+    times.get(times.len() / 2).copied().unwrap_or(0)
+}
+
+/// BIP141 witness commitment for a coinbase-first transaction list:
+/// SHA256d(witness merkle root || witness reserved value). The coinbase's
+/// wtxid counts as all zeros, so the commitment doesn't depend on it.
+pub fn calculate_witness_commitment(
+    transactions: &[Transaction],
+    reserved_value: &[u8],
+) -> Result<String, String> {
+    let wtxids = transactions
+        .iter()
+        .map(|tx| tx.calculate_wtxid())
+        .collect::<Result<Vec<_>, _>>()?;
+    let witness_root = MerkleTree::from_ids(&wtxids)?
+        .root()
+        .ok_or("No transactions to commit to")?;
+    let mut commitment_input = witness_root.to_vec();
+    commitment_input.extend(reserved_value);
+    Ok(hex::encode(double_sha256(&commitment_input)))
+}
+
+/// `OP_RETURN OP_PUSHBYTES_36 aa21a9ed <commitment>`
+pub fn witness_commitment_script(commitment: &str) -> Result<Vec<u8>, String> {
+    let commitment = hex::decode(commitment).map_err(|_| "Invalid witness commitment")?;
+    let mut script = vec![0x6a, 0x24];
+    script.extend(WITNESS_COMMITMENT_HEADER);
+    script.extend(commitment);
+    Ok(script)
+}
+
 pub fn double_sha256(data: &[u8]) -> Vec<u8> {
     let hash1 = Sha256::digest(data);
-    let hash2 = Sha256::digest(&hash1);
+    let hash2 = Sha256::digest(hash1);
     hash2.to_vec()
 }
@@ -0,0 +1,157 @@
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Set with a fixed capacity that evicts its oldest entry when full.
+struct BoundedSet<K> {
+    entries: HashSet<K>,
+    order: VecDeque<K>,
+    capacity: usize,
+}
+
+impl<K: Hash + Eq + Clone> BoundedSet<K> {
+    fn new(capacity: usize) -> Self {
+        BoundedSet {
+            entries: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    fn contains(&self, key: &K) -> bool {
+        self.entries.contains(key)
+    }
+
+    fn insert(&mut self, key: K) {
+        if self.capacity == 0 || self.entries.contains(&key) {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.order.push_back(key.clone());
+        self.entries.insert(key);
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[derive(Default)]
+pub struct CacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CacheStats {
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    fn record(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (hits, misses) = (self.hits(), self.misses());
+        let lookups = hits + misses;
+        let rate = if lookups == 0 {
+            0.0
+        } else {
+            hits as f64 * 100.0 / lookups as f64
+        };
+        write!(
+            f,
+            "{} hits, {} misses ({:.1}% hit rate)",
+            hits, misses, rate
+        )
+    }
+}
+
+type SigCacheKey = ([u8; 32], Vec<u8>, Vec<u8>);
+
+/// Signatures that already verified, keyed by (sighash, pubkey, signature).
+///
+/// Only successful verifications are stored, so a hit means the signature is
+/// known to be valid for that message and key.
+pub struct SigCache {
+    entries: Mutex<BoundedSet<SigCacheKey>>,
+    pub stats: CacheStats,
+}
+
+impl SigCache {
+    pub fn new(capacity: usize) -> Self {
+        SigCache {
+            entries: Mutex::new(BoundedSet::new(capacity)),
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn contains(&self, sighash: &[u8; 32], pubkey: &[u8], sig: &[u8]) -> bool {
+        let key = (*sighash, pubkey.to_vec(), sig.to_vec());
+        let hit = self.entries.lock().unwrap().contains(&key);
+        self.stats.record(hit);
+        hit
+    }
+
+    pub fn insert(&self, sighash: &[u8; 32], pubkey: &[u8], sig: &[u8]) {
+        let key = (*sighash, pubkey.to_vec(), sig.to_vec());
+        self.entries.lock().unwrap().insert(key);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+type ScriptCacheKey = (String, [u8; 32], u32);
+
+/// Transactions whose inputs all passed script checks, keyed by wtxid, a hash
+/// of the outputs they spend and the verification flags that were in force.
+///
+/// The wtxid only names the outpoints, so the spent outputs are part of the
+/// key: the same transaction checked against other prevout amounts or
+/// scripts must be verified again.
+pub struct ScriptCache {
+    entries: Mutex<BoundedSet<ScriptCacheKey>>,
+    pub stats: CacheStats,
+}
+
+impl ScriptCache {
+    pub fn new(capacity: usize) -> Self {
+        ScriptCache {
+            entries: Mutex::new(BoundedSet::new(capacity)),
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn contains(&self, wtxid: &str, spent_outputs: &[u8; 32], flags: u32) -> bool {
+        let key = (wtxid.to_string(), *spent_outputs, flags);
+        let hit = self.entries.lock().unwrap().contains(&key);
+        self.stats.record(hit);
+        hit
+    }
+
+    pub fn insert(&self, wtxid: &str, spent_outputs: &[u8; 32], flags: u32) {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert((wtxid.to_string(), *spent_outputs, flags));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use num_bigint::BigUint;
+
+use crate::block::{median_time_past, BlockHeader};
+use crate::consensus::{ConsensusParams, CHALLENGE_BITS, MEDIAN_TIME_SPAN};
+use crate::pow::{bits_to_target, block_work, hash_meets_target, next_work_required};
+use crate::tx::ByteReader;
+
+/// A header the chain has accepted.
+pub struct ChainEntry {
+    pub header: BlockHeader,
+    pub height: u32,
+    /// Total work of the chain ending at this header.
+    pub chain_work: BigUint,
+    /// Order the header was first seen in, which breaks ties in work.
+    pub sequence: usize,
+}
+
+/// Every accepted header, linked by `previous_block_hash`, with the chain of
+/// most cumulative work as the active one; of equal-work chains, the one
+/// whose tip was seen first. Headers are appended to a file as
+/// `<height> <header hex>` lines in the order they were seen.
+pub struct HeaderChain {
+    path: PathBuf,
+    /// Keyed by block hash in display order.
+    entries: HashMap<String, ChainEntry>,
+    /// Hashes of the active chain, root first.
+    active: Vec<String>,
+    next_sequence: usize,
+}
+
+impl HeaderChain {
+    /// Loads the headers in `path`, or starts an empty chain if it doesn't
+    /// exist yet. Every header is checked again as it is loaded.
+    pub fn open(path: &Path, params: &ConsensusParams) -> Result<HeaderChain, String> {
+        let mut chain = HeaderChain {
+            path: path.to_path_buf(),
+            entries: HashMap::new(),
+            active: Vec::new(),
+            next_sequence: 0,
+        };
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(chain),
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+
+        for (i, line) in text.lines().enumerate() {
+            let line_error = |e: String| format!("{} line {}: {}", path.display(), i + 1, e);
+            let (height, header) = line
+                .split_once(' ')
+                .ok_or_else(|| line_error("Expected <height> <header hex>".to_string()))?;
+            let height = height
+                .parse()
+                .map_err(|_| line_error(format!("Invalid height: {}", height)))?;
+            let header = hex::decode(header)
+                .map_err(|_| line_error("Invalid header hex".to_string()))
+                .and_then(|bytes| {
+                    BlockHeader::parse(&mut ByteReader::new(&bytes)).map_err(line_error)
+                })?;
+            chain.insert(header, height, params).map_err(line_error)?;
+        }
+        Ok(chain)
+    }
+
+    /// Checks `header` against its parent, records it and appends it to the
+    /// file. The first header of an empty chain is its root and may have any
+    /// parent; it is taken to be at `root_height`.
+    pub fn add(
+        &mut self,
+        header: BlockHeader,
+        root_height: u32,
+        params: &ConsensusParams,
+    ) -> Result<&ChainEntry, String> {
+        let hash = self.insert(header, root_height, params)?;
+        let entry = &self.entries[&hash];
+        let line = format!("{} {}", entry.height, hex::encode(entry.header.serialize()));
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| format!("Failed to open {}: {}", self.path.display(), e))?;
+        writeln!(file, "{}", line)
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))?;
+        Ok(entry)
+    }
+
+    /// Removes the active tip and rewrites the file. The active chain falls
+    /// back to the tip's parent unless another branch has more work.
+    pub fn remove_tip(&mut self) -> Result<ChainEntry, String> {
+        let hash = self.active.pop().ok_or("The header chain is empty")?;
+        let entry = self.entries.remove(&hash).unwrap();
+        // Most work, then first seen, as if the headers were added again
+        let best = self
+            .entries
+            .iter()
+            .max_by(|a, b| {
+                a.1.chain_work
+                    .cmp(&b.1.chain_work)
+                    .then(b.1.sequence.cmp(&a.1.sequence))
+            })
+            .map(|(hash, _)| hash.clone());
+        self.active = match best {
+            Some(best) => self.path_to(&best),
+            None => Vec::new(),
+        };
+
+        let mut entries = self.entries.values().collect::<Vec<_>>();
+        entries.sort_by_key(|entry| entry.sequence);
+        let text = entries
+            .iter()
+            .map(|entry| {
+                format!(
+                    "{} {}\n",
+                    entry.height,
+                    hex::encode(entry.header.serialize())
+                )
+            })
+            .collect::<String>();
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, text).map_err(|e| format!("Failed to write {}: {}", tmp.display(), e))?;
+        fs::rename(&tmp, &self.path)
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e))?;
+        Ok(entry)
+    }
+
+    pub fn tip(&self) -> Option<&ChainEntry> {
+        self.active.last().map(|hash| &self.entries[hash])
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&ChainEntry> {
+        self.entries.get(hash)
+    }
+
+    /// Bits the child of block `hash` must have if it has time `next_time`.
+    pub fn next_bits(
+        &self,
+        hash: &str,
+        next_time: u32,
+        params: &ConsensusParams,
+    ) -> Result<u32, String> {
+        let interval = params.difficulty_adjustment_interval() as usize;
+        let headers = self.ancestors(hash, interval);
+        next_work_required(&headers, self.entries[hash].height, next_time, params)
+    }
+
+    /// Latest time a child of block `hash` can have and still need the bits
+    /// it needs at `time`, or None if later times don't change them. Only
+    /// testnet's 20-minute rule makes the required bits depend on the time.
+    pub fn bits_time_limit(&self, hash: &str, time: u32, params: &ConsensusParams) -> Option<u32> {
+        let parent = &self.entries[hash];
+        let retarget = (parent.height + 1).is_multiple_of(params.difficulty_adjustment_interval());
+        if !params.allow_min_difficulty_blocks || retarget {
+            return None;
+        }
+        let boundary = parent.header.time + params.pow_target_spacing * 2;
+        (time <= boundary).then_some(boundary)
+    }
+
+    /// Median time past of block `hash`, which its children's time must exceed.
+    pub fn median_time_past(&self, hash: &str) -> u32 {
+        median_time_past(&self.ancestors(hash, MEDIAN_TIME_SPAN))
+    }
+
+    /// Records `header` without writing it and returns its hash.
+    fn insert(
+        &mut self,
+        header: BlockHeader,
+        root_height: u32,
+        params: &ConsensusParams,
+    ) -> Result<String, String> {
+        let hash = header.hash();
+        if self.entries.contains_key(&hash) {
+            return Err(format!("Block {} is already in the chain", hash));
+        }
+        let target = bits_to_target(header.bits)?;
+        // Chains mined here start at the challenge target, which keeps its
+        // bits until the first retarget caps them, so it is let through on
+        // every network
+        if target > params.pow_limit && header.bits != CHALLENGE_BITS {
+            return Err(format!(
+                "Block {} has bits {:08x} above the pow limit",
+                hash, header.bits
+            ));
+        }
+        if !hash_meets_target(&hash, &target) {
+            return Err(format!(
+                "Block {} doesn't meet its target {:08x}",
+                hash, header.bits
+            ));
+        }
+
+        let parent_hash = display_order(&header.previous_block_hash);
+        let (height, parent_work) = match self.entries.get(&parent_hash) {
+            Some(parent) => {
+                let required = self.next_bits(&parent_hash, header.time, params)?;
+                if header.bits != required {
+                    return Err(format!(
+                        "Block {} has bits {:08x} but {:08x} are required",
+                        hash, header.bits, required
+                    ));
+                }
+                let mtp = self.median_time_past(&parent_hash);
+                if header.time <= mtp {
+                    return Err(format!(
+                        "Block {} has time {} at or before the median time past {}",
+                        hash, header.time, mtp
+                    ));
+                }
+                (parent.height + 1, parent.chain_work.clone())
+            }
+            None if self.entries.is_empty() => (root_height, BigUint::from(0u32)),
+            None => {
+                return Err(format!(
+                    "Block {} builds on unknown block {}",
+                    hash, parent_hash
+                ))
+            }
+        };
+
+        let chain_work = parent_work + block_work(header.bits)?;
+        let is_best = self.tip().is_none_or(|tip| chain_work > tip.chain_work);
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.entries.insert(
+            hash.clone(),
+            ChainEntry {
+                header,
+                height,
+                chain_work,
+                sequence,
+            },
+        );
+        if is_best {
+            self.active = self.path_to(&hash);
+        }
+        Ok(hash)
+    }
+
+    /// Up to `count` headers ending with `hash`, oldest first.
+    fn ancestors(&self, hash: &str, count: usize) -> Vec<BlockHeader> {
+        let mut headers = Vec::new();
+        let mut entry = self.entries.get(hash);
+        while let Some(current) = entry {
+            if headers.len() == count {
+                break;
+            }
+            headers.push(current.header.clone());
+            entry = self
+                .entries
+                .get(&display_order(&current.header.previous_block_hash));
+        }
+        headers.reverse();
+        headers
+    }
+
+    /// Hashes from the root to `hash`.
+    pub fn path_to(&self, hash: &str) -> Vec<String> {
+        let mut path = vec![hash.to_string()];
+        let mut entry = &self.entries[hash];
+        while let Some(parent) = self
+            .entries
+            .get(&display_order(&entry.header.previous_block_hash))
+        {
+            path.push(parent.header.hash());
+            entry = parent;
+        }
+        path.reverse();
+        path
+    }
+}
+
+/// Reverses a hash kept in serialized byte order for display.
+pub fn display_order(hash: &str) -> String {
+    let mut bytes = hex::decode(hash).unwrap_or_default();
+    bytes.reverse();
+    hex::encode(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::network::Network;
+
+    const REGTEST_BITS: u32 = 0x207fffff;
+
+    /// A regtest header on `parent` (display order, empty for a root) with
+    /// valid proof of work; `time` tells siblings apart.
+    fn header(parent: &str, time: u32) -> BlockHeader {
+        header_with_bits(parent, time, REGTEST_BITS)
+    }
+
+    fn header_with_bits(parent: &str, time: u32, bits: u32) -> BlockHeader {
+        let mut header = BlockHeader {
+            version: 0x20000000,
+            previous_block_hash: match parent {
+                "" => "0".repeat(64),
+                parent => display_order(parent),
+            },
+            merkle_root: "11".repeat(32),
+            time,
+            bits,
+            nonce: 0,
+        };
+        let target = bits_to_target(bits).unwrap();
+        while !hash_meets_target(&header.hash(), &target) {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    #[test]
+    fn equal_work_ties_go_to_the_first_seen_after_remove_and_reload() {
+        let path = env::temp_dir().join(format!("chain-test-{}.txt", process::id()));
+        let _ = fs::remove_file(&path);
+        let params = ConsensusParams::for_network(Network::Regtest);
+        let mut chain = HeaderChain::open(&path, &params).unwrap();
+
+        let time = 1_600_000_000;
+        let add = |chain: &mut HeaderChain, parent: &str, time: u32| {
+            let header = header(parent, time);
+            chain.add(header.clone(), 0, &params).unwrap();
+            header.hash()
+        };
+        let root = add(&mut chain, "", time);
+        let tip = add(&mut chain, &root, time + 1);
+        // Siblings of the tip, each with as much work as it
+        let first = add(&mut chain, &root, time + 2);
+        let mut later = Vec::new();
+        for i in 3..10 {
+            later.push(add(&mut chain, &root, time + i));
+        }
+        assert_eq!(chain.tip().unwrap().header.hash(), tip);
+
+        chain.remove_tip().unwrap();
+        assert_eq!(chain.tip().unwrap().header.hash(), first);
+        let reloaded = HeaderChain::open(&path, &params).unwrap();
+        assert_eq!(reloaded.tip().unwrap().header.hash(), first);
+
+        chain.remove_tip().unwrap();
+        assert_eq!(chain.tip().unwrap().header.hash(), later[0]);
+        let reloaded = HeaderChain::open(&path, &params).unwrap();
+        assert_eq!(reloaded.tip().unwrap().header.hash(), later[0]);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn targets_above_the_pow_limit_are_rejected_except_the_challenge() {
+        let path = env::temp_dir().join(format!("chain-limit-test-{}.txt", process::id()));
+        let _ = fs::remove_file(&path);
+        let params = ConsensusParams::for_network(Network::Mainnet);
+        let mut chain = HeaderChain::open(&path, &params).unwrap();
+
+        let time = 1_600_000_000;
+        let result = chain.add(header("", time), 0, &params).map(|_| ());
+        assert!(result.unwrap_err().contains("above the pow limit"));
+
+        let root = header_with_bits("", time, CHALLENGE_BITS);
+        chain.add(root.clone(), 0, &params).unwrap();
+        let child = header_with_bits(&root.hash(), time + 1, CHALLENGE_BITS);
+        chain.add(child, 0, &params).unwrap();
+        assert_eq!(chain.tip().unwrap().height, 1);
+        fs::remove_file(path).unwrap();
+    }
+}
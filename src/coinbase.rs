@@ -1,25 +1,205 @@
+use std::str::FromStr;
+
+use crate::address::address_to_script;
+use crate::network::Network;
+use crate::script::{decode_script_num, disassemble, push_data, push_int};
 use crate::tx::{Input, Output, PrevOut, Transaction};
-use ripemd::{Digest, Ripemd160};
-use sha2::Sha256;
+
+/// Consensus bounds on the coinbase scriptsig length.
+pub const MIN_COINBASE_SCRIPTSIG_LEN: usize = 2;
+pub const MAX_COINBASE_SCRIPTSIG_LEN: usize = 100;
+
+/// A pool member and their proportional weight in the reward split,
+/// given on the command line as `ADDRESS:WEIGHT`.
+#[derive(Debug, Clone)]
+pub struct Payout {
+    pub address: String,
+    pub weight: u64,
+}
+
+/// Fixed amount paid to the pool operator before the split, as `ADDRESS:SATS`.
+#[derive(Debug, Clone)]
+pub struct OperatorFee {
+    pub address: String,
+    pub amount: u64,
+}
+
+impl FromStr for Payout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Payout, String> {
+        let (address, weight) = split_address_amount(s)?;
+        Ok(Payout { address, weight })
+    }
+}
+
+impl FromStr for OperatorFee {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OperatorFee, String> {
+        let (address, amount) = split_address_amount(s)?;
+        Ok(OperatorFee { address, amount })
+    }
+}
+
+fn split_address_amount(s: &str) -> Result<(String, u64), String> {
+    let (address, amount) = s
+        .rsplit_once(':')
+        .ok_or_else(|| format!("Expected ADDRESS:AMOUNT, got {}", s))?;
+    let amount = amount
+        .parse()
+        .map_err(|_| format!("Invalid amount in {}", s))?;
+    Ok((address.to_string(), amount))
+}
+
+/// Splits `total` between the operator fee and the payouts.
+///
+/// The operator gets its fixed amount first. The rest is divided in proportion
+/// to the payout weights, rounding down, and the leftover satoshis go one each
+/// to the payouts with the largest rounding loss (earlier entries win ties), so
+/// the same inputs always produce the same amounts and nothing is lost.
+pub fn split_reward(
+    total: u64,
+    payouts: &[Payout],
+    operator_fee: Option<&OperatorFee>,
+) -> Result<Vec<(String, u64)>, String> {
+    let fee = operator_fee.map_or(0, |f| f.amount);
+    let remaining = total
+        .checked_sub(fee)
+        .ok_or_else(|| format!("Operator fee {} exceeds the coinbase value {}", fee, total))?;
+
+    let total_weight: u128 = payouts.iter().map(|p| p.weight as u128).sum();
+    if total_weight == 0 {
+        return Err("Payout weights must not all be zero".to_string());
+    }
+
+    let mut amounts = Vec::with_capacity(payouts.len());
+    let mut losses = Vec::with_capacity(payouts.len());
+    for (i, payout) in payouts.iter().enumerate() {
+        let exact = remaining as u128 * payout.weight as u128;
+        amounts.push((exact / total_weight) as u64);
+        losses.push((exact % total_weight, i));
+    }
+
+    let leftover = remaining - amounts.iter().sum::<u64>();
+    losses.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    for &(_, i) in losses.iter().take(leftover as usize) {
+        amounts[i] += 1;
+    }
+
+    let mut split = Vec::new();
+    if let Some(operator_fee) = operator_fee {
+        split.push((operator_fee.address.clone(), operator_fee.amount));
+    }
+    for (payout, amount) in payouts.iter().zip(amounts) {
+        split.push((payout.address.clone(), amount));
+    }
+    Ok(split)
+}
+
+/// Builds the coinbase scriptsig `<height> <extranonce> <tag>`.
+///
+/// The height comes first as BIP34 requires, pushed exactly like
+/// `CScript() << height`. The extranonce is a fixed 8 byte push so rolling it
+/// never changes the coinbase size.
+pub fn coinbase_scriptsig(height: u32, extranonce: u64, tag: &[u8]) -> Result<Vec<u8>, String> {
+    let mut script = push_int(height as i64);
+    script.extend(push_data(&extranonce.to_le_bytes()));
+    if !tag.is_empty() {
+        script.extend(push_data(tag));
+    }
+
+    if !(MIN_COINBASE_SCRIPTSIG_LEN..=MAX_COINBASE_SCRIPTSIG_LEN).contains(&script.len()) {
+        return Err(format!(
+            "Coinbase scriptsig is {} bytes, must be {}-{} (tag is {} bytes)",
+            script.len(),
+            MIN_COINBASE_SCRIPTSIG_LEN,
+            MAX_COINBASE_SCRIPTSIG_LEN,
+            tag.len()
+        ));
+    }
+    Ok(script)
+}
+
+/// Reads the BIP34 height back out of a coinbase scriptsig. The height must
+/// be the first push and encoded exactly as `coinbase_scriptsig` writes it.
+pub fn coinbase_height(scriptsig: &[u8]) -> Result<u32, String> {
+    let (&opcode, rest) = scriptsig.split_first().ok_or("Empty coinbase scriptsig")?;
+    let height = match opcode {
+        0x00 => 0,
+        0x51..=0x60 => (opcode - 0x50) as i64,
+        0x01..=0x05 => decode_script_num(
+            rest.get(..opcode as usize)
+                .ok_or("Coinbase height push runs past the end")?,
+        ),
+        _ => return Err("Coinbase scriptsig doesn't start with a height push".to_string()),
+    };
+    let height =
+        u32::try_from(height).map_err(|_| format!("Invalid coinbase height {}", height))?;
+    if !scriptsig.starts_with(&push_int(height as i64)) {
+        return Err(format!(
+            "Coinbase height {} is not minimally encoded",
+            height
+        ));
+    }
+    Ok(height)
+}
+
+/// Adds one to the 8 byte extranonce that `coinbase_scriptsig` put after the
+/// height, giving the coinbase (and so the merkle root) a fresh hash.
+pub fn increment_extranonce(coinbase: &mut Transaction) -> Result<(), String> {
+    let input = coinbase.vin.first_mut().ok_or("Coinbase has no input")?;
+    let mut scriptsig = hex::decode(&input.scriptsig)
+        .map_err(|_| format!("Invalid coinbase scriptsig: {}", input.scriptsig))?;
+    let start = push_int(coinbase_height(&scriptsig)? as i64).len();
+    let push = scriptsig
+        .get_mut(start..start + 9)
+        .filter(|push| push[0] == 8)
+        .ok_or("Coinbase scriptsig has no extranonce")?;
+    let extranonce = u64::from_le_bytes(push[1..].try_into().unwrap()).wrapping_add(1);
+    push[1..].copy_from_slice(&extranonce.to_le_bytes());
+
+    input.scriptsig = hex::encode(&scriptsig);
+    input.scriptsig_asm = disassemble(&scriptsig);
+    Ok(())
+}
 
 pub fn create_coinbase_transaction(
     block_reward: u64,
     total_fees: u64,
-    miner_address: String,
-) -> Transaction {
+    scriptsig: &[u8],
+    payouts: &[Payout],
+    operator_fee: Option<&OperatorFee>,
+    network: Network,
+) -> Result<Transaction, String> {
     // The output value of the coinbase transaction is the sum of block reward and total fees
     let output_value = block_reward + total_fees;
 
-    // Convert the miner's address to its public key hash (PKH)
-    let pkh = hash_public_key(&miner_address);
-
-    // Create the P2PKH script using the public key hash
-    let script = format!("76a914{}88ac", hex::encode(pkh));
+    // Decode each address into the script it pays to (P2PKH, P2SH, P2WPKH, P2WSH or P2TR).
+    // Zero-value shares are left out rather than creating dust outputs.
+    let mut outputs = Vec::new();
+    for (address, amount) in split_reward(output_value, payouts, operator_fee)? {
+        let script = address_to_script(&address, network)?;
+        if amount > 0 {
+            outputs.push(Output::from_script(&script, amount, network));
+        }
+    }
 
-    // Create the witness commitment output
-    // let witness_commitment_script = create_witness_commitment_script();
+    // With no subsidy and no fees there is nothing to pay, but a coinbase
+    // still needs an output; a zero-value one is valid
+    if outputs.is_empty() {
+        let script = address_to_script(&payouts[0].address, network)?;
+        outputs.push(Output::from_script(&script, 0, network));
+    }
+    let paid: u64 = outputs.iter().map(|o| o.value).sum();
+    if paid != output_value {
+        return Err(format!(
+            "Coinbase outputs pay {} but reward + fees is {}",
+            paid, output_value
+        ));
+    }
 
-    Transaction {
+    Ok(Transaction {
         version: 1,  // Version of the transaction format
         locktime: 0, // Typically 0 for coinbase transactions
         vin: vec![Input {
@@ -34,31 +214,13 @@ pub fn create_coinbase_transaction(
                 scriptpubkey_address: String::new(),
                 value: 0, // No input value
             },
-            scriptsig: String::from("1600140f1c83b7ea9e7fefd2b10aac8c680ede85e3d50f"), // Miners can include arbitrary data here
-            scriptsig_asm: String::new(),
-            witness: Some(vec![String::from("00")]), // Witness reserved value
+            scriptsig: hex::encode(scriptsig), // Height, extranonce and pool tag
+            scriptsig_asm: disassemble(scriptsig),
+            witness: None, // Set with the witness commitment once the block's wtxids are known
             is_coinbase: true,
             sequence: 0xffffffff, // Full sequence
         }],
-        vout: vec![Output {
-            // The output sending the reward to the miner's address
-            scriptpubkey: script.clone(),
-            scriptpubkey_asm: script,
-            scriptpubkey_type: String::from("p2pkh"), // Pay to public key hash
-            scriptpubkey_address: Some(miner_address),
-            value: output_value,
-        }],
-    }
-}
-
-fn hash_public_key(public_key: &str) -> Vec<u8> {
-    let mut hasher = Sha256::new();
-    hasher.update(public_key.as_bytes());
-    let sha256_hash = hasher.finalize();
-
-    let mut hasher = Ripemd160::new();
-    hasher.update(sha256_hash);
-    let pkh = hasher.finalize();
-
-    pkh.to_vec()
+        // The outputs sending the reward to the operator and pool members
+        vout: outputs,
+    })
 }
@@ -0,0 +1,236 @@
+use std::path::PathBuf;
+
+use crate::coinbase::{OperatorFee, Payout};
+use crate::consensus::{BIP320_VERSION_MASK, MAX_FUTURE_BLOCK_TIME};
+use crate::network::Network;
+use crate::parallel::default_threads;
+
+const DEFAULT_SIG_CACHE_SIZE: usize = 100_000;
+const DEFAULT_SCRIPT_CACHE_SIZE: usize = 20_000;
+const DEFAULT_MAINNET_ADDRESS: &str = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4";
+/// Last block before the fourth halving, i.e. the 6.25 BTC subsidy era.
+const DEFAULT_MAINNET_HEIGHT: u32 = 839_999;
+/// The first block after genesis, with the full subsidy on any halving
+/// interval.
+const DEFAULT_TEST_HEIGHT: u32 = 1;
+const DEFAULT_BLOCK_FILE: &str = "output.txt";
+/// Roll nTime at most 10 minutes ahead, well inside the 2 hour limit.
+const DEFAULT_MAX_TIME_OFFSET: u32 = 10 * 60;
+
+/// What the program was asked to do.
+pub enum Mode {
+    /// Build and mine a block from the mempool (the default).
+    Mine,
+    /// `verify [FILE]`: check output.txt or a raw block.
+    Verify(PathBuf),
+    /// `next-bits FILE`: print the bits required after the headers in FILE,
+    /// one hex header per line ending with the parent of `--height`.
+    NextBits(PathBuf),
+    /// `prove TXID [FILE]`: print the merkle proof for a transaction in
+    /// output.txt or a raw block.
+    Prove(String, PathBuf),
+    /// `merkleblock TXID[,TXID...] [FILE]`: print the BIP37 `merkleblock`
+    /// payload matching those transactions.
+    MerkleBlock(Vec<String>, PathBuf),
+    /// `verify-merkleblock HEX`: check a `merkleblock` payload against its
+    /// header's merkle root and list the matched transactions.
+    VerifyMerkleBlock(String),
+    /// `decode-block FILE`: print a raw block, or every block of a
+    /// `blk*.dat` file, as `getblock` verbosity 2 JSON.
+    DecodeBlock(PathBuf),
+    /// `disconnect-tip`: undo the last block connected to `--utxo` and drop
+    /// its header from `--chain`, so its transactions are minable again.
+    DisconnectTip,
+}
+
+/// Command line settings, e.g. `cargo run -- --threads 8`.
+pub struct Config {
+    pub threads: usize,
+    pub sig_cache_size: usize,
+    pub script_cache_size: usize,
+    pub network: Network,
+    /// Rewrite inconsistent asm/type/address fields instead of dropping the file.
+    pub fix_script_fields: bool,
+    /// Addresses the coinbase reward is split between.
+    pub payouts: Vec<Payout>,
+    pub operator_fee: Option<OperatorFee>,
+    /// Height of the block being built, committed in the coinbase (BIP34).
+    /// Defaults to 839999 on mainnet and 1 on testnet and regtest.
+    pub height: u32,
+    /// Arbitrary pool tag appended to the coinbase scriptsig.
+    pub coinbase_tag: String,
+    pub mode: Mode,
+    /// Version bits the miner may roll (BIP320), given in hex.
+    pub version_mask: u32,
+    /// Seconds the miner may roll nTime past the current time.
+    pub max_time_offset: u32,
+    /// Also write the mined block serialized in full: binary for a `.dat`
+    /// path, hex otherwise.
+    pub raw_block: Option<PathBuf>,
+    /// Header chain file to build on and extend. The block then follows the
+    /// chain's tip, which also decides its height, bits and earliest time;
+    /// `--height` only applies to the first block of a new chain.
+    pub chain: Option<PathBuf>,
+    /// Directory with the UTXO snapshot and the connected blocks. Needs
+    /// `--chain`; the mempool is filtered to what the set can still spend.
+    pub utxo: Option<PathBuf>,
+    /// Block in `--chain` to build on instead of the tip, to start or extend
+    /// a competing branch.
+    pub parent: Option<String>,
+    /// Blocks to mine in a row, each on the last; 0 keeps going until the
+    /// mempool is empty or nothing left in it can be mined yet. More than one
+    /// needs `--utxo`.
+    pub blocks: u32,
+}
+
+impl Config {
+    pub fn from_args() -> Result<Config, String> {
+        let mut config = Config {
+            threads: default_threads(),
+            sig_cache_size: DEFAULT_SIG_CACHE_SIZE,
+            script_cache_size: DEFAULT_SCRIPT_CACHE_SIZE,
+            network: Network::Mainnet,
+            fix_script_fields: false,
+            payouts: Vec::new(),
+            operator_fee: None,
+            height: 0,
+            coinbase_tag: String::new(),
+            mode: Mode::Mine,
+            version_mask: BIP320_VERSION_MASK,
+            max_time_offset: DEFAULT_MAX_TIME_OFFSET,
+            raw_block: None,
+            chain: None,
+            utxo: None,
+            parent: None,
+            blocks: 1,
+        };
+
+        let mut height = None;
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--threads" => {
+                    config.threads = parse_value(&arg, args.next())?;
+                    if config.threads == 0 {
+                        return Err("--threads must be at least 1".to_string());
+                    }
+                }
+                "--sig-cache-size" => config.sig_cache_size = parse_value(&arg, args.next())?,
+                "--script-cache-size" => config.script_cache_size = parse_value(&arg, args.next())?,
+                "--network" => config.network = parse_value(&arg, args.next())?,
+                "--fix-script-fields" => config.fix_script_fields = true,
+                "--address" => config.payouts.push(Payout {
+                    address: parse_value(&arg, args.next())?,
+                    weight: 1,
+                }),
+                "--payout" => config.payouts.push(parse_value(&arg, args.next())?),
+                "--operator-fee" => config.operator_fee = Some(parse_value(&arg, args.next())?),
+                "--height" => height = Some(parse_value(&arg, args.next())?),
+                "--coinbase-tag" => config.coinbase_tag = parse_value(&arg, args.next())?,
+                "--version-mask" => {
+                    let value = args.next().ok_or("Missing value for --version-mask")?;
+                    config.version_mask =
+                        u32::from_str_radix(value.trim_start_matches("0x"), 16)
+                            .map_err(|e| format!("Invalid value for {}: {} ({})", arg, value, e))?;
+                    if config.version_mask & !BIP320_VERSION_MASK != 0 {
+                        return Err(format!(
+                            "--version-mask must be within {:#010x}",
+                            BIP320_VERSION_MASK
+                        ));
+                    }
+                }
+                "--max-time-offset" => {
+                    config.max_time_offset = parse_value(&arg, args.next())?;
+                    if config.max_time_offset > MAX_FUTURE_BLOCK_TIME {
+                        return Err(format!(
+                            "--max-time-offset must be at most {}",
+                            MAX_FUTURE_BLOCK_TIME
+                        ));
+                    }
+                }
+                "--chain" => config.chain = Some(parse_value(&arg, args.next())?),
+                "--utxo" => config.utxo = Some(parse_value(&arg, args.next())?),
+                "--parent" => config.parent = Some(parse_value(&arg, args.next())?),
+                "--blocks" => config.blocks = parse_value(&arg, args.next())?,
+                "--raw-block" => config.raw_block = Some(parse_value(&arg, args.next())?),
+                "verify" if matches!(config.mode, Mode::Mine) => {
+                    config.mode = Mode::Verify(PathBuf::from(DEFAULT_BLOCK_FILE))
+                }
+                "next-bits" if matches!(config.mode, Mode::Mine) => {
+                    config.mode = Mode::NextBits(parse_value(&arg, args.next())?)
+                }
+                "prove" if matches!(config.mode, Mode::Mine) => {
+                    let txid = args.next().ok_or("Missing txid for prove")?;
+                    config.mode = Mode::Prove(txid, PathBuf::from(DEFAULT_BLOCK_FILE))
+                }
+                "merkleblock" if matches!(config.mode, Mode::Mine) => {
+                    let txids = args.next().ok_or("Missing txids for merkleblock")?;
+                    let txids = txids.split(',').map(str::to_string).collect();
+                    config.mode = Mode::MerkleBlock(txids, PathBuf::from(DEFAULT_BLOCK_FILE))
+                }
+                "verify-merkleblock" if matches!(config.mode, Mode::Mine) => {
+                    let payload = args.next().ok_or("Missing merkleblock hex")?;
+                    config.mode = Mode::VerifyMerkleBlock(payload)
+                }
+                "decode-block" if matches!(config.mode, Mode::Mine) => {
+                    config.mode = Mode::DecodeBlock(parse_value(&arg, args.next())?)
+                }
+                "disconnect-tip" if matches!(config.mode, Mode::Mine) => {
+                    config.mode = Mode::DisconnectTip
+                }
+                path if !path.starts_with("--") => match &mut config.mode {
+                    Mode::Verify(file) | Mode::Prove(_, file) | Mode::MerkleBlock(_, file) => {
+                        *file = PathBuf::from(path)
+                    }
+                    _ => return Err(format!("Unknown argument: {}", path)),
+                },
+                other => return Err(format!("Unknown argument: {}", other)),
+            }
+        }
+
+        // A mainnet height would mean a zero subsidy on regtest's 150 block
+        // halving interval
+        config.height = height.unwrap_or(match config.network {
+            Network::Mainnet => DEFAULT_MAINNET_HEIGHT,
+            Network::Testnet | Network::Regtest => DEFAULT_TEST_HEIGHT,
+        });
+
+        if config.utxo.is_some() && config.chain.is_none() {
+            return Err("--utxo needs --chain".to_string());
+        }
+        if config.blocks != 1 && config.utxo.is_none() {
+            return Err("--blocks needs --chain and --utxo".to_string());
+        }
+        if config.parent.is_some() && config.chain.is_none() {
+            return Err("--parent needs --chain".to_string());
+        }
+        if matches!(config.mode, Mode::DisconnectTip) && config.utxo.is_none() {
+            return Err("disconnect-tip needs --chain and --utxo".to_string());
+        }
+
+        if config.payouts.is_empty() && matches!(config.mode, Mode::Mine) {
+            if config.network != Network::Mainnet {
+                return Err(format!(
+                    "--address or --payout is required on {}",
+                    config.network
+                ));
+            }
+            config.payouts.push(Payout {
+                address: DEFAULT_MAINNET_ADDRESS.to_string(),
+                weight: 1,
+            });
+        }
+        Ok(config)
+    }
+}
+
+fn parse_value<T>(flag: &str, value: Option<String>) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let value = value.ok_or_else(|| format!("Missing value for {}", flag))?;
+    value
+        .parse()
+        .map_err(|e| format!("Invalid value for {}: {} ({})", flag, value, e))
+}
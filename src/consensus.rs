@@ -0,0 +1,93 @@
+use num_bigint::BigUint;
+
+use crate::network::Network;
+
+pub const COIN: u64 = 100_000_000;
+const INITIAL_SUBSIDY: u64 = 50 * COIN;
+
+/// Block limits from BIP141: weight units and sigop cost.
+pub const MAX_BLOCK_WEIGHT: usize = 4_000_000;
+pub const MAX_BLOCK_SIGOPS_COST: usize = 80_000;
+
+/// Confirmations a coinbase output needs before it can be spent, counting
+/// its own block.
+pub const COINBASE_MATURITY: u32 = 100;
+
+/// The challenge's target, 0000ffff00..00 (see README), in compact form.
+/// It is easier than the mainnet and testnet pow limit.
+pub const CHALLENGE_BITS: u32 = 0x1f00ffff;
+
+/// Block version with the BIP9 top bits 001 and no deployment bits set.
+pub const VERSIONBITS_TOP_BITS: u32 = 0x20000000;
+/// Version bits miners may use as extra nonce space (BIP320).
+pub const BIP320_VERSION_MASK: u32 = 0x1fffe000;
+/// How far ahead of network-adjusted time a block timestamp may be, in seconds.
+pub const MAX_FUTURE_BLOCK_TIME: u32 = 2 * 60 * 60;
+/// Number of previous blocks whose median time a new block must exceed.
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Per-network consensus constants.
+pub struct ConsensusParams {
+    /// Blocks between subsidy halvings.
+    pub subsidy_halving_interval: u32,
+    /// Easiest target a block may have.
+    pub pow_limit: BigUint,
+    /// Seconds a retarget period is meant to take (two weeks).
+    pub pow_target_timespan: u32,
+    /// Seconds between blocks the target aims for.
+    pub pow_target_spacing: u32,
+    /// Testnet's 20-minute rule: a block more than two spacings after its
+    /// parent may be mined at the pow limit.
+    pub allow_min_difficulty_blocks: bool,
+    /// Regtest never changes the difficulty at a retarget.
+    pub no_retargeting: bool,
+}
+
+impl ConsensusParams {
+    pub fn for_network(network: Network) -> ConsensusParams {
+        // 2^224 - 1 and 2^255 - 1, i.e. bits 0x1d00ffff and 0x207fffff
+        let max_target = |bits: u32| (BigUint::from(1u32) << bits) - 1u32;
+        match network {
+            Network::Mainnet => ConsensusParams {
+                subsidy_halving_interval: 210_000,
+                pow_limit: max_target(224),
+                pow_target_timespan: 14 * 24 * 60 * 60,
+                pow_target_spacing: 10 * 60,
+                allow_min_difficulty_blocks: false,
+                no_retargeting: false,
+            },
+            Network::Testnet => ConsensusParams {
+                subsidy_halving_interval: 210_000,
+                pow_limit: max_target(224),
+                pow_target_timespan: 14 * 24 * 60 * 60,
+                pow_target_spacing: 10 * 60,
+                allow_min_difficulty_blocks: true,
+                no_retargeting: false,
+            },
+            Network::Regtest => ConsensusParams {
+                subsidy_halving_interval: 150,
+                pow_limit: max_target(255),
+                pow_target_timespan: 14 * 24 * 60 * 60,
+                pow_target_spacing: 10 * 60,
+                allow_min_difficulty_blocks: true,
+                no_retargeting: true,
+            },
+        }
+    }
+
+    /// Blocks per retarget period, 2016.
+    pub fn difficulty_adjustment_interval(&self) -> u32 {
+        self.pow_target_timespan / self.pow_target_spacing
+    }
+
+    /// New coins created by the block at `height`: 50 BTC halved every
+    /// `subsidy_halving_interval` blocks, and zero once the shift would
+    /// exceed the width of the value (as in `GetBlockSubsidy`).
+    pub fn block_subsidy(&self, height: u32) -> u64 {
+        let halvings = height / self.subsidy_halving_interval;
+        if halvings >= 64 {
+            return 0;
+        }
+        INITIAL_SUBSIDY >> halvings
+    }
+}
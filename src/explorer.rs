@@ -0,0 +1,212 @@
+use serde::Serialize;
+use serde_json::value::RawValue;
+
+use crate::block::{double_sha256, Block};
+use crate::chain::display_order;
+use crate::coinbase::coinbase_height;
+use crate::consensus::COIN;
+use crate::pow::difficulty;
+use crate::script::{core_asm, ScriptType};
+use crate::tx::{serialize_varint, Transaction};
+
+/// A block laid out like `getblock` at verbosity 2, with the txids, wtxids,
+/// sizes and height recomputed from the transactions.
+#[derive(Serialize)]
+pub struct BlockView {
+    hash: String,
+    size: usize,
+    strippedsize: usize,
+    weight: usize,
+    /// From the coinbase (BIP34); left out if it has none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    version: u32,
+    #[serde(rename = "versionHex")]
+    version_hex: String,
+    merkleroot: String,
+    tx: Vec<TxView>,
+    time: u32,
+    nonce: u32,
+    bits: String,
+    difficulty: f64,
+    #[serde(rename = "nTx")]
+    n_tx: usize,
+    previousblockhash: String,
+}
+
+#[derive(Serialize)]
+struct TxView {
+    txid: String,
+    hash: String,
+    version: i32,
+    size: usize,
+    vsize: usize,
+    weight: usize,
+    locktime: u32,
+    vin: Vec<InputView>,
+    vout: Vec<OutputView>,
+    hex: String,
+}
+
+/// A coinbase input only has `coinbase`; any other input has the outpoint
+/// and `scriptSig` instead.
+#[derive(Serialize)]
+struct InputView {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    coinbase: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    txid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vout: Option<u32>,
+    #[serde(rename = "scriptSig", skip_serializing_if = "Option::is_none")]
+    script_sig: Option<ScriptSigView>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    txinwitness: Option<Vec<String>>,
+    sequence: u32,
+}
+
+#[derive(Serialize)]
+struct ScriptSigView {
+    asm: String,
+    hex: String,
+}
+
+#[derive(Serialize)]
+struct OutputView {
+    /// In BTC with all 8 decimals, a JSON number like Core's.
+    value: Box<RawValue>,
+    n: usize,
+    #[serde(rename = "scriptPubKey")]
+    script_pubkey: ScriptPubKeyView,
+}
+
+#[derive(Serialize)]
+struct ScriptPubKeyView {
+    asm: String,
+    hex: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+    #[serde(rename = "type")]
+    script_type: String,
+}
+
+impl BlockView {
+    pub fn new(block: &Block) -> Result<BlockView, String> {
+        let header = &block.header;
+        let tx = block
+            .transactions
+            .iter()
+            .map(TxView::new)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let header_and_count = 80 + serialize_varint(block.transactions.len() as u64).len();
+        let size = header_and_count + tx.iter().map(|tx| tx.size).sum::<usize>();
+        let strippedsize = header_and_count
+            + block
+                .transactions
+                .iter()
+                .map(|tx| tx.base_size())
+                .sum::<usize>();
+        let height = block
+            .transactions
+            .first()
+            .filter(|tx| tx.is_coinbase())
+            .and_then(|coinbase| hex::decode(&coinbase.vin[0].scriptsig).ok())
+            .and_then(|scriptsig| coinbase_height(&scriptsig).ok());
+
+        Ok(BlockView {
+            hash: header.hash(),
+            size,
+            strippedsize,
+            weight: strippedsize * 3 + size,
+            height,
+            version: header.version,
+            version_hex: format!("{:08x}", header.version),
+            merkleroot: display_order(&header.merkle_root),
+            n_tx: tx.len(),
+            tx,
+            time: header.time,
+            nonce: header.nonce,
+            bits: format!("{:08x}", header.bits),
+            difficulty: difficulty(header.bits),
+            previousblockhash: display_order(&header.previous_block_hash),
+        })
+    }
+}
+
+impl TxView {
+    fn new(tx: &Transaction) -> Result<TxView, String> {
+        let weight = tx.weight();
+        let serialized = Block::serialize_transaction(tx);
+        // Core reports the coinbase's real wtxid here, not the zero hash the
+        // witness commitment uses
+        let mut hash = double_sha256(&serialized);
+        hash.reverse();
+        let vin = tx
+            .vin
+            .iter()
+            .map(|input| {
+                let (coinbase, txid, vout, script_sig) = if input.is_coinbase {
+                    (Some(input.scriptsig.clone()), None, None, None)
+                } else {
+                    let script = hex::decode(&input.scriptsig).unwrap_or_default();
+                    let script_sig = ScriptSigView {
+                        asm: core_asm(&script, true),
+                        hex: input.scriptsig.clone(),
+                    };
+                    (
+                        None,
+                        Some(input.txid.clone()),
+                        Some(input.vout),
+                        Some(script_sig),
+                    )
+                };
+                InputView {
+                    coinbase,
+                    txid,
+                    vout,
+                    script_sig,
+                    txinwitness: input.witness.clone(),
+                    sequence: input.sequence,
+                }
+            })
+            .collect();
+        let vout = tx
+            .vout
+            .iter()
+            .enumerate()
+            .map(|(n, output)| {
+                let script = hex::decode(&output.scriptpubkey).unwrap_or_default();
+                OutputView {
+                    value: btc_amount(output.value),
+                    n,
+                    script_pubkey: ScriptPubKeyView {
+                        asm: core_asm(&script, false),
+                        hex: output.scriptpubkey.clone(),
+                        address: output.scriptpubkey_address.clone(),
+                        script_type: ScriptType::classify(&script).core_name().to_string(),
+                    },
+                }
+            })
+            .collect();
+
+        Ok(TxView {
+            txid: tx.calculate_txid()?,
+            hash: hex::encode(hash),
+            version: tx.version,
+            size: tx.total_size(),
+            vsize: weight.div_ceil(4),
+            weight,
+            locktime: tx.locktime,
+            vin,
+            vout,
+            hex: hex::encode(serialized),
+        })
+    }
+}
+
+/// Satoshis as a BTC amount with 8 decimals (`ValueFromAmount`).
+fn btc_amount(value: u64) -> Box<RawValue> {
+    RawValue::from_string(format!("{}.{:08}", value / COIN, value % COIN))
+        .expect("a decimal number is valid JSON")
+}
@@ -1,22 +1,56 @@
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io;
 use std::path::Path;
 use std::path::PathBuf;
+use std::time::SystemTime;
 
+mod address;
 mod block;
+mod cache;
+mod chain;
 mod coinbase;
+mod config;
+mod consensus;
+mod explorer;
+mod merkle;
+mod merkleblock;
+mod network;
 mod p2pkh;
+mod p2tr;
+mod p2wpkh;
+mod parallel;
+mod pow;
+mod script;
+mod sighash;
+mod template;
 mod tx;
+mod utxo;
 mod validate;
-use tx::Transaction;
+mod verify;
+use tx::{serialize_varint, ByteReader, Transaction};
 
-use crate::block::Block;
-use crate::block::BlockHeader;
-use crate::coinbase::create_coinbase_transaction;
+use crate::block::{median_time_past, unix_time, Block, BlockHeader, MiningJob};
+use crate::chain::HeaderChain;
+use crate::config::{Config, Mode};
+use crate::consensus::{ConsensusParams, CHALLENGE_BITS, MAX_BLOCK_WEIGHT, VERSIONBITS_TOP_BITS};
+use crate::explorer::BlockView;
+use crate::merkle::{id_to_hash, MerkleProof, MerkleTree};
+use crate::merkleblock::MerkleBlock;
+use crate::network::Network;
+use crate::parallel::parallel_map;
+use crate::pow::{block_work, difficulty, next_work_required};
+use crate::template::BlockTemplate;
+use crate::utxo::{Reorg, UtxoSet};
+use crate::validate::{validate_transactions, SigVerifier, TxError, VERIFY_ALL};
+use crate::verify::{read_raw_blocks, verify_block, BlockFile};
 
-// use validate::validate_transaction;
+type MempoolEntry = (PathBuf, Transaction);
 
-fn read_transactions_from_dir(dir: &Path) -> io::Result<(Vec<Transaction>, usize, usize)> {
+fn read_transactions_from_dir(
+    dir: &Path,
+    threads: usize,
+) -> io::Result<(Vec<MempoolEntry>, usize, usize)> {
     let mut entries = fs::read_dir(dir)?
         .filter_map(|e| e.ok())
         .map(|e| e.path())
@@ -26,28 +60,37 @@ fn read_transactions_from_dir(dir: &Path) -> io::Result<(Vec<Transaction>, usize
     // Sort the entries by their path names
     entries.sort();
 
+    // Read and parse on the worker pool; results keep the sorted file order
+    let parsed = parallel_map(&entries, threads, |path| {
+        fs::read_to_string(path)
+            .ok()
+            .map(|data| serde_json::from_str::<Transaction>(&data))
+    });
+
     let mut transactions = Vec::new();
-    let mut total_files = 0;
+    let total_files = entries.len();
     let mut failed_parses = 0;
 
-    for path in entries {
-        total_files += 1;
-        println!("{:?}", path);
-        match fs::read_to_string(&path) {
-            Ok(data) => match serde_json::from_str::<Transaction>(&data) {
-                Ok(transaction) => transactions.push(transaction),
-                Err(_) => failed_parses += 1,
-            },
-            Err(_) => {}
+    for (path, result) in entries.into_iter().zip(parsed) {
+        match result {
+            Some(Ok(transaction)) => transactions.push((path, transaction)),
+            Some(Err(_)) => failed_parses += 1,
+            None => {}
         }
     }
 
     Ok((transactions, total_files, failed_parses))
 }
 
-fn get_tx() -> Vec<Transaction> {
-    let dir = Path::new("./mempool");
-    let txs = match read_transactions_from_dir(dir) {
+const MEMPOOL_DIR: &str = "./mempool";
+/// The mempool files' addresses are always mainnet ones.
+const MEMPOOL_NETWORK: Network = Network::Mainnet;
+
+/// Reads and validates the mempool. Returns the valid transactions and the
+/// txids of every transaction in it, valid or not.
+fn get_tx(config: &Config, verifier: &SigVerifier) -> (Vec<Transaction>, HashSet<String>) {
+    let dir = Path::new(MEMPOOL_DIR);
+    let txs = match read_transactions_from_dir(dir, config.threads) {
         Ok((transactions, total_files, failed_parses)) => {
             println!("Successfully parsed transactions: {}", transactions.len());
             println!("Total files: {}", total_files);
@@ -56,13 +99,59 @@ fn get_tx() -> Vec<Transaction> {
         }
         Err(e) => panic!("Error reading transactions: {}", e),
     };
+    let all_txids = parallel_map(&txs, config.threads, |(_, tx)| tx.calculate_txid().ok())
+        .into_iter()
+        .flatten()
+        .collect::<HashSet<_>>();
+
+    // Flag files whose asm/type/address fields don't describe their script hex.
+    // They are dropped, or repaired from the hex with --fix-script-fields.
+    // The files are Esplora mainnet data whatever network we mine on.
+    let mismatches = parallel_map(&txs, config.threads, |(_, tx)| {
+        tx.script_field_mismatches(MEMPOOL_NETWORK)
+    });
+    let mut inconsistent = 0;
+    let txs = txs
+        .into_iter()
+        .zip(mismatches)
+        .filter_map(|((path, mut tx), mismatches)| {
+            if mismatches.is_empty() {
+                return Some(tx);
+            }
+            inconsistent += 1;
+            println!("Inconsistent script fields in {:?}:", path);
+            for m in mismatches {
+                println!("  {}", m);
+            }
+            if config.fix_script_fields && tx.recompute_script_fields(MEMPOOL_NETWORK).is_ok() {
+                return Some(tx);
+            }
+            None
+        })
+        .collect::<Vec<_>>();
+    println!("Inconsistent script fields: {}", inconsistent);
+
+    let results = validate_transactions(&txs, verifier, VERIFY_ALL, config.threads);
+
+    // Unsupported transactions may be valid, but a block is only as good as
+    // its least checked transaction, so they stay out too
     let mut valid_txs = vec![];
-    for tx in txs {
-        if tx.is_basic_valid() {
-            valid_txs.push(tx);
+    let mut invalid = 0;
+    let mut unsupported = 0;
+    for (tx, result) in txs.into_iter().zip(results) {
+        match result {
+            Ok(()) => valid_txs.push(tx),
+            Err(TxError::Invalid(_)) => invalid += 1,
+            Err(TxError::Unsupported(_)) => unsupported += 1,
         }
     }
-    valid_txs
+    println!("Valid transactions: {}", valid_txs.len());
+    println!("Invalid transactions: {}", invalid);
+    println!(
+        "Unsupported transactions (scripts not evaluated, left out): {}",
+        unsupported
+    );
+    (valid_txs, all_txids)
 }
 
 fn select_tx_for_block(txs: Vec<Transaction>) -> Vec<Transaction> {
@@ -82,19 +171,54 @@ fn select_tx_for_block(txs: Vec<Transaction>) -> Vec<Transaction> {
     });
     let mut c = 0;
 
+    // A transaction can only follow its in-mempool parents, so one whose
+    // parent isn't in the block yet waits for it. Outpoints already spent by
+    // a selected transaction can't be spent again.
+    let txids = txs_sorted
+        .iter()
+        .map(|tx| tx.calculate_txid().unwrap_or_default())
+        .collect::<Vec<_>>();
+    let in_mempool = txids.iter().cloned().collect::<HashSet<_>>();
+    let mut selected_txids = HashSet::new();
+    let mut spent = HashSet::new();
+    let mut waiting: HashMap<String, Vec<(String, Transaction)>> = HashMap::new();
+
     // Select transactions to maximize fee and fit within block weight
-    for tx in txs_sorted {
-        let tx_weight = tx.weight();
-        if total_weight + tx_weight <= MAX_BLOCK_WEIGHT {
+    'select: for (txid, tx) in txids.into_iter().zip(txs_sorted) {
+        let mut ready = vec![(txid, tx)];
+        while let Some((txid, tx)) = ready.pop() {
+            let missing_parent = tx.vin.iter().find(|input| {
+                in_mempool.contains(&input.txid) && !selected_txids.contains(&input.txid)
+            });
+            if let Some(parent) = missing_parent {
+                waiting
+                    .entry(parent.txid.clone())
+                    .or_default()
+                    .push((txid, tx));
+                continue;
+            }
+            if tx
+                .vin
+                .iter()
+                .any(|input| spent.contains(&(input.txid.clone(), input.vout)))
+            {
+                continue;
+            }
+
+            let tx_weight = tx.weight();
+            if total_weight + tx_weight > MAX_BLOCK_WEIGHT {
+                // If adding this transaction exceeds the block weight limit, stop adding.
+                break 'select;
+            }
+            spent.extend(tx.vin.iter().map(|input| (input.txid.clone(), input.vout)));
             selected_txs.push(tx);
             c += 1;
             total_weight += tx_weight;
             if c > 2000 {
-                break;
+                break 'select;
             }
-        } else {
-            // If adding this transaction exceeds the block weight limit, stop adding.
-            break;
+            ready.extend(waiting.remove(&txid).unwrap_or_default());
+            selected_txids.insert(txid);
         }
     }
 
@@ -106,43 +230,651 @@ fn select_tx_for_block(txs: Vec<Transaction>) -> Vec<Transaction> {
     selected_txs
 }
 
-fn main() {
-    let txs = get_tx();
+/// Checks a mined block against consensus rules and the mempool it was built
+/// from, printing one line per check. Returns whether every check passed.
+fn run_verify(config: &Config, path: &Path) -> bool {
+    let file = match BlockFile::read(path, config.network) {
+        Ok(file) => file,
+        Err(e) => {
+            println!("Failed to read block: {}", e);
+            return false;
+        }
+    };
+
+    let (entries, _, _) = read_transactions_from_dir(Path::new(MEMPOOL_DIR), config.threads)
+        .unwrap_or_else(|e| panic!("Error reading transactions: {}", e));
+    let txids = parallel_map(&entries, config.threads, |(_, tx)| tx.calculate_txid());
+    let mempool = entries
+        .into_iter()
+        .zip(txids)
+        .filter_map(|((_, tx), txid)| Some((txid.ok()?, tx)))
+        .collect::<HashMap<_, _>>();
+
+    let params = ConsensusParams::for_network(config.network);
+    let verifier = SigVerifier::new(config.sig_cache_size, config.script_cache_size);
+    let checks = verify_block(&file, &mempool, &params, &verifier, config.threads);
 
-    let mut valid = select_tx_for_block(txs);
+    let mut passed = true;
+    for check in checks {
+        match check.result {
+            Ok(()) => println!("{}: ok", check.name),
+            Err(e) => {
+                println!("{}: FAILED ({})", check.name, e);
+                passed = false;
+            }
+        }
+    }
+    passed
+}
 
-    let total_fees = valid.iter().fold(0, |acc, x| acc + x.fee());
+/// Required bits for the block at `config.height`, following the headers in
+/// `path`, and the median time past its timestamp must exceed. The new block
+/// is assumed to be mined now.
+fn next_bits(config: &Config, path: &Path) -> Result<(u32, u32), String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let headers = text
+        .split_whitespace()
+        .map(|line| {
+            let bytes = hex::decode(line).map_err(|_| format!("Invalid header hex: {}", line))?;
+            BlockHeader::parse(&mut ByteReader::new(&bytes))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
 
-    let br = 6_250_000_000;
-    let cb_tx = create_coinbase_transaction(br, total_fees, "".to_owned());
-    let mut valid_tx = vec![cb_tx];
-    valid_tx.append(&mut valid);
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32;
+    let params = ConsensusParams::for_network(config.network);
+    let tip_height = config.height.saturating_sub(1);
+    let bits = next_work_required(&headers, tip_height, now, &params)?;
+    Ok((bits, median_time_past(&headers)))
+}
 
-    // println!("mai{:?}", valid_tx[1].calculate_txid());
-    // println!("mai{:?}", valid_tx[1].calculate_wtxid());
+/// Prints the merkle branch for `txid` in the block at `path` and checks it
+/// against the header's merkle root. Returns whether it matched.
+fn prove(config: &Config, txid: &str, path: &Path) -> Result<bool, String> {
+    let file = BlockFile::read(path, config.network)?;
+    let (header, txids) = file.header_and_txids();
+    let index = txids
+        .iter()
+        .position(|id| id == txid)
+        .ok_or_else(|| format!("{} is not in {}", txid, path.display()))?;
 
-    // println!("mai{:?}",     valid_tx[1].vin[0].txid);// 000cb561188c762c81f76976f816829424e2af9e0e491c617b7bf41038df3d35
+    let proof = MerkleTree::from_ids(txids)?.proof(index).unwrap();
+    println!("Transaction {} at index {}", txid, index);
+    for sibling in &proof.branch {
+        println!("  {}", hex::encode(sibling));
+    }
 
-    // 69074bd90317c507b367c40dee722821c8954eeb84c9e24e29050b0c85d1d422
-    // 7533d87ec9e2f0eda1298c2e2e37141c275358c4884fd90fbb0f87d67e5f0ce0
-    // 7533d87ec9e2f0eda1298c2e2e37141c275358c4884fd90fbb0f87d67e5f0ce0
-    // println!("{:?}", valid_tx[1])
+    let root: [u8; 32] = hex::decode(&header.merkle_root)
+        .ok()
+        .and_then(|root| root.try_into().ok())
+        .ok_or("Invalid merkle root in header")?;
+    let matches = proof.verify(id_to_hash(txid)?, root);
+    println!(
+        "Proof {} the header merkle root {}",
+        if matches { "matches" } else { "does not match" },
+        header.merkle_root
+    );
+    Ok(matches)
+}
 
-    let difficulty_target = "0000ffff00000000000000000000000000000000000000000000000000000000";
+/// Hex `merkleblock` payload for the block at `path` matching `matched`.
+fn merkle_block(config: &Config, matched: &[String], path: &Path) -> Result<String, String> {
+    let file = BlockFile::read(path, config.network)?;
+    let (header, txids) = file.header_and_txids();
+    if let Some(missing) = matched.iter().find(|txid| !txids.contains(txid)) {
+        return Err(format!("{} is not in {}", missing, path.display()));
+    }
+    let merkle_block = MerkleBlock::new(header.clone(), txids, matched)?;
+    Ok(hex::encode(merkle_block.serialize()))
+}
+
+/// Parses a hex `merkleblock` payload, checks it against its header and
+/// prints the matched transactions.
+fn verify_merkle_block(payload: &str) -> Result<(), String> {
+    let data = hex::decode(payload).map_err(|_| "Invalid merkleblock hex".to_string())?;
+    let merkle_block = MerkleBlock::parse(&data)?;
+    let matches = merkle_block.verify()?;
+    println!(
+        "Block {}: {} of {} transactions matched",
+        merkle_block.header.hash(),
+        matches.len(),
+        merkle_block.tree.transaction_count
+    );
+    for (index, txid) in matches {
+        println!("  {} {}", index, txid);
+    }
+    Ok(())
+}
+
+/// `getblock`-style JSON for the raw block(s) at `path`: an object for a
+/// single block and an array for a `blk*.dat` file. Warns about blocks whose
+/// recomputed merkle root doesn't match the header.
+fn decode_block(config: &Config, path: &Path) -> Result<String, String> {
+    let blocks = read_raw_blocks(path, config.network)?;
+    let mut views = Vec::new();
+    for block in &blocks {
+        let root = MerkleTree::from_ids(&block.txids)?.root_hex();
+        if root.as_ref() != Some(&block.header.merkle_root) {
+            eprintln!(
+                "Warning: block {} has merkle root {} but its transactions give {}",
+                block.header.hash(),
+                block.header.merkle_root,
+                root.unwrap_or_default()
+            );
+        }
+        views.push(BlockView::new(block)?);
+    }
+    let json = match views.as_slice() {
+        [view] => serde_json::to_string_pretty(view),
+        _ => serde_json::to_string_pretty(&views),
+    };
+    json.map_err(|e| format!("Failed to encode JSON: {}", e))
+}
+
+/// Loads the UTXO set in `dir`, or starts one from the valid mempool
+/// transactions if there is none yet; `mempool_txids` has every transaction
+/// in the mempool. A set left behind or ahead of the chain's tip by an
+/// interrupted run is moved to the tip with the stored blocks.
+fn open_utxo(
+    dir: &Path,
+    mempool: &[Transaction],
+    mempool_txids: &HashSet<String>,
+    chain: &HeaderChain,
+    network: Network,
+) -> Result<UtxoSet, String> {
+    let tip = chain.tip().map(|tip| tip.header.hash()).unwrap_or_default();
+    let mut utxo = match UtxoSet::load(dir)? {
+        Some(utxo) => utxo,
+        None => UtxoSet::from_mempool(dir, mempool, mempool_txids),
+    };
+    if utxo.best_block != tip {
+        let reorg = utxo.reorg_to(chain, &tip, network).map_err(|e| {
+            format!(
+                "UTXO set is at block {:?} and can't be moved to the chain tip {:?}: {}",
+                utxo.best_block, tip, e
+            )
+        })?;
+        print_reorg(&reorg, &tip);
+        utxo.save()?;
+    }
+    Ok(utxo)
+}
+
+/// What a mined block contains, for the per-block report.
+struct BlockSummary {
+    height: u32,
+    /// Not counting the coinbase.
+    transactions: usize,
+    fees: u64,
+    weight: usize,
+}
+
+type MinedBlock = (Block, BlockSummary);
+
+/// Builds a template from `mempool` on `parent`, or on the chain's tip, and
+/// mines it. With a chain and UTXO set, the block is connected to both and
+/// the mempool is filtered to what is still spendable after it. With
+/// `--blocks 0`, no block is mined if nothing left in the mempool can go in
+/// one yet.
+fn mine_block(
+    config: &Config,
+    params: &ConsensusParams,
+    verifier: &SigVerifier,
+    chain: Option<&mut HeaderChain>,
+    mut utxo: Option<&mut UtxoSet>,
+    mempool: Vec<Transaction>,
+    parent: Option<String>,
+) -> Result<(Option<MinedBlock>, Vec<Transaction>), String> {
+    let base = match (chain.as_deref(), parent) {
+        (Some(chain), Some(parent)) => {
+            let entry = chain
+                .get(&parent)
+                .ok_or_else(|| format!("Block {} is not in the header chain", parent))?;
+            Some((parent, entry.height))
+        }
+        (Some(chain), None) => chain.tip().map(|tip| (tip.header.hash(), tip.height)),
+        _ => None,
+    };
+
+    // Move the UTXO set to the block being built on
+    let mut mempool = mempool;
+    if let (Some(utxo), Some(chain), Some((base_hash, _))) =
+        (utxo.as_deref_mut(), chain.as_deref(), &base)
+    {
+        if utxo.best_block != *base_hash {
+            let reorg = utxo
+                .reorg_to(chain, base_hash, config.network)
+                .map_err(|e| format!("Failed to move the UTXO set to {}: {}", base_hash, e))?;
+            print_reorg(&reorg, base_hash);
+            mempool = return_to_mempool(mempool, reorg.transactions, verifier, config.threads);
+        }
+    }
+    let height = base
+        .as_ref()
+        .map_or(config.height, |(_, height)| height + 1);
+    let mut candidates = mempool.clone();
+    if let Some(utxo) = utxo.as_deref() {
+        let before = mempool.len();
+        mempool = utxo.filter_mempool(mempool);
+        println!(
+            "Inputs spent or missing from the UTXO set: {}",
+            before - mempool.len()
+        );
+        // Immature spends stay in the mempool for a later block
+        let (mature, immature, errors) = utxo.split_immature(mempool.clone(), height);
+        for error in &errors {
+            println!("Excluded {}", error);
+        }
+        if !immature.is_empty() {
+            println!(
+                "Held back until coinbase outputs mature: {}",
+                immature.len()
+            );
+        }
+        candidates = mature;
+    }
+    // Everything left only spends immature coinbase outputs
+    if config.blocks == 0 && candidates.is_empty() && !mempool.is_empty() {
+        return Ok((None, mempool));
+    }
+
+    let selected = select_tx_for_block(candidates);
+    let template = BlockTemplate::new(config, params, height, selected)
+        .map_err(|e| format!("Failed to create coinbase: {}", e))?;
+    println!(
+        "Block {}: subsidy {} + fees {}",
+        template.height, template.subsidy, template.total_fees
+    );
+    let fees = template.total_fees;
+    let valid_tx = template.transactions;
+
+    // The bits are for the time mining starts at; rolling the time must not
+    // cross into different bits
+    let (bits, previous_block_hash, min_time, time_limit) = match (chain.as_deref(), &base) {
+        (Some(chain), Some((base_hash, _))) => {
+            let min_time = chain.median_time_past(base_hash) + 1;
+            let start_time = unix_time().max(min_time);
+            let bits = chain
+                .next_bits(base_hash, start_time, params)
+                .map_err(|e| format!("Failed to get the required bits: {}", e))?;
+            let previous_block_hash = hex::encode(id_to_hash(base_hash)?);
+            (
+                bits,
+                previous_block_hash,
+                min_time,
+                chain.bits_time_limit(base_hash, start_time, params),
+            )
+        }
+        // A new chain starts at the challenge target with no parent
+        _ => (CHALLENGE_BITS, "00".repeat(32), 0, None),
+    };
 
     let mut block = Block {
         header: BlockHeader {
-            version: 1,
-            previous_block_hash: "".to_string(),
+            version: VERSIONBITS_TOP_BITS,
+            previous_block_hash,
             merkle_root: "".to_string(),
             time: 0,
-            bits: 0,
+            bits,
             nonce: 0,
         },
         transactions: valid_tx,
         txids: vec![],
+        coinbase_branch: MerkleProof::default(),
+    };
+
+    println!(
+        "Mining at bits {:08x} (difficulty {}, {} expected hashes)",
+        block.header.bits,
+        difficulty(block.header.bits),
+        block_work(block.header.bits)?
+    );
+    let job = MiningJob {
+        threads: config.threads,
+        version_mask: config.version_mask,
+        min_time,
+        max_time_offset: config.max_time_offset,
+        time_limit,
+    };
+    let stats = block
+        .mine(&job)
+        .map_err(|e| format!("Failed to mine block: {}", e))?;
+    println!(
+        "Mined block {} in {:.2?}: {} hashes ({:.0} H/s)",
+        block.header.hash(),
+        stats.elapsed,
+        stats.hashes,
+        stats.hash_rate()
+    );
+
+    // Connect and save first: a block the UTXO set rejects must not reach
+    // the chain file, and a set saved ahead of the chain can be moved back
+    if let Some(utxo) = utxo.as_deref_mut() {
+        utxo.store_block(&block)
+            .and_then(|()| utxo.connect_block(&block, height))
+            .and_then(|()| utxo.save())
+            .map_err(|e| format!("Failed to connect the block to the UTXO set: {}", e))?;
+    }
+    if let Some(chain) = chain {
+        let entry = chain
+            .add(block.header.clone(), height, params)
+            .map_err(|e| format!("Failed to extend the header chain: {}", e))?;
+        println!(
+            "Added block {} at height {} (chainwork {:x})",
+            block.header.hash(),
+            entry.height,
+            entry.chain_work
+        );
+        let tip = chain.tip().unwrap().header.hash();
+        println!("Chain tip {}", tip);
+
+        // A side branch that didn't overtake the tip leaves the set on it
+        if let Some(utxo) = utxo.as_deref_mut().filter(|utxo| utxo.best_block != tip) {
+            let reorg = utxo
+                .reorg_to(chain, &tip, config.network)
+                .map_err(|e| format!("Failed to move the UTXO set back to {}: {}", tip, e))?;
+            print_reorg(&reorg, &tip);
+            utxo.save()?;
+            mempool = return_to_mempool(mempool, reorg.transactions, verifier, config.threads);
+        }
+    }
+    // Included transactions now spend coins that are gone
+    if let Some(utxo) = utxo.as_deref() {
+        mempool = utxo.filter_mempool(mempool);
+    }
+
+    let header_and_count = 80 + serialize_varint(block.transactions.len() as u64).len();
+    let summary = BlockSummary {
+        height,
+        transactions: block.transactions.len() - 1,
+        fees,
+        weight: header_and_count * 4
+            + block
+                .transactions
+                .iter()
+                .map(|tx| tx.weight())
+                .sum::<usize>(),
+    };
+    Ok((Some((block, summary)), mempool))
+}
+
+/// Adds transactions from disconnected blocks back to the mempool, checking
+/// the scripts of those it didn't already have. Those that conflict with the
+/// new branch are dropped afterwards by the UTXO filter.
+fn return_to_mempool(
+    mut mempool: Vec<Transaction>,
+    returned: Vec<Transaction>,
+    verifier: &SigVerifier,
+    threads: usize,
+) -> Vec<Transaction> {
+    let known = mempool
+        .iter()
+        .filter_map(|tx| tx.calculate_txid().ok())
+        .collect::<HashSet<_>>();
+    let new = returned
+        .into_iter()
+        .filter(|tx| tx.calculate_txid().is_ok_and(|txid| !known.contains(&txid)))
+        .collect::<Vec<_>>();
+
+    let results = validate_transactions(&new, verifier, VERIFY_ALL, threads);
+    let mut invalid = 0;
+    let mut unsupported = 0;
+    for (tx, result) in new.into_iter().zip(results) {
+        match result {
+            Ok(()) => mempool.push(tx),
+            Err(TxError::Invalid(_)) => invalid += 1,
+            Err(TxError::Unsupported(_)) => unsupported += 1,
+        }
+    }
+    if invalid > 0 {
+        println!("Invalid transactions from disconnected blocks: {}", invalid);
+    }
+    if unsupported > 0 {
+        println!(
+            "Unsupported transactions from disconnected blocks: {}",
+            unsupported
+        );
+    }
+    mempool
+}
+
+fn print_reorg(reorg: &Reorg, target: &str) {
+    println!(
+        "Reorganized to {}: disconnected {} blocks, connected {}; {} transactions returned to the mempool",
+        target,
+        reorg.disconnected,
+        reorg.connected,
+        reorg.transactions.len()
+    );
+}
+
+/// Disconnects the tip block from the UTXO set and drops its header from
+/// the chain. If a competing branch now has the most work, the set is moved
+/// onto it. Returns the block hash, its height and how many transactions it
+/// gave back to the mempool that the new tip hasn't confirmed.
+fn disconnect_tip(config: &Config) -> Result<(String, u32, usize), String> {
+    let params = ConsensusParams::for_network(config.network);
+    let mut chain = HeaderChain::open(config.chain.as_deref().unwrap(), &params)?;
+    let mut utxo = open_utxo(
+        config.utxo.as_deref().unwrap(),
+        &[],
+        &HashSet::new(),
+        &chain,
+        config.network,
+    )?;
+    let (block, mut transactions) = utxo.disconnect_tip(config.network)?;
+    let entry = chain.remove_tip()?;
+
+    let tip = chain.tip().map(|tip| tip.header.hash()).unwrap_or_default();
+    if utxo.best_block != tip {
+        let reorg = utxo
+            .reorg_to(&chain, &tip, config.network)
+            .map_err(|e| format!("Failed to move the UTXO set to {}: {}", tip, e))?;
+        print_reorg(&reorg, &tip);
+        transactions.extend(reorg.transactions);
+        transactions = utxo.filter_mempool(transactions);
+    }
+    utxo.save()?;
+    Ok((block.header.hash(), entry.height, transactions.len()))
+}
+
+fn main() {
+    let config = match Config::from_args() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    match &config.mode {
+        Mode::Mine => {}
+        Mode::Verify(path) => {
+            let passed = run_verify(&config, path);
+            std::process::exit(if passed { 0 } else { 1 });
+        }
+        Mode::Prove(txid, path) => match prove(&config, txid, path) {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        Mode::MerkleBlock(txids, path) => match merkle_block(&config, txids, path) {
+            Ok(payload) => {
+                println!("{}", payload);
+                return;
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        Mode::VerifyMerkleBlock(payload) => match verify_merkle_block(payload) {
+            Ok(()) => return,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        Mode::DecodeBlock(path) => match decode_block(&config, path) {
+            Ok(json) => {
+                println!("{}", json);
+                return;
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        Mode::DisconnectTip => match disconnect_tip(&config) {
+            Ok((hash, height, returned)) => {
+                println!(
+                    "Disconnected block {} at height {}; {} transactions are back in the mempool",
+                    hash, height, returned
+                );
+                return;
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        Mode::NextBits(path) => match next_bits(&config, path) {
+            Ok((bits, median_time)) => {
+                println!(
+                    "Block {} requires bits {:08x} (difficulty {}) and a time after {}",
+                    config.height,
+                    bits,
+                    difficulty(bits),
+                    median_time
+                );
+                return;
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+    }
+
+    let verifier = SigVerifier::new(config.sig_cache_size, config.script_cache_size);
+    let (txs, mempool_txids) = get_tx(&config, &verifier);
+    println!(
+        "Signature cache ({} entries): {}",
+        verifier.sig_cache.len(),
+        verifier.sig_cache.stats
+    );
+    println!(
+        "Script cache ({} entries): {}",
+        verifier.script_cache.len(),
+        verifier.script_cache.stats
+    );
+
+    let params = ConsensusParams::for_network(config.network);
+    let mut chain = match config
+        .chain
+        .as_deref()
+        .map(|path| HeaderChain::open(path, &params))
+    {
+        Some(Ok(chain)) => Some(chain),
+        Some(Err(e)) => {
+            eprintln!("Failed to load the header chain: {}", e);
+            std::process::exit(1);
+        }
+        None => None,
+    };
+    let mut utxo = match (config.utxo.as_deref(), chain.as_ref()) {
+        (Some(dir), Some(chain)) => {
+            match open_utxo(dir, &txs, &mempool_txids, chain, config.network) {
+                Ok(utxo) => Some(utxo),
+                Err(e) => {
+                    eprintln!("Failed to load the UTXO set: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let mut mempool = txs;
+    let mut parent = config.parent.clone();
+    let mut mined = Vec::new();
+    let mut last = None;
+    loop {
+        let result = mine_block(
+            &config,
+            &params,
+            &verifier,
+            chain.as_mut(),
+            utxo.as_mut(),
+            mempool,
+            parent.take(),
+        );
+        let (block, summary) = match result {
+            Ok((Some(mined), remaining)) => {
+                mempool = remaining;
+                mined
+            }
+            Ok((None, remaining)) => {
+                println!(
+                    "Nothing in the mempool can be mined until coinbase outputs mature: {} left",
+                    remaining.len()
+                );
+                mempool = remaining;
+                break;
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        println!(
+            "Block {} at height {}: {} transactions, fees {}, weight {} ({:.1}% full)",
+            block.header.hash(),
+            summary.height,
+            summary.transactions,
+            summary.fees,
+            summary.weight,
+            summary.weight as f64 * 100.0 / MAX_BLOCK_WEIGHT as f64
+        );
+        mined.push(summary);
+        last = Some(block);
+
+        if mined.len() as u32 == config.blocks || mempool.is_empty() {
+            break;
+        }
+    }
+    if mined.len() > 1 {
+        println!(
+            "Mined {} blocks: fees {}, {} transactions, {} left in the mempool",
+            mined.len(),
+            mined.iter().map(|summary| summary.fees).sum::<u64>(),
+            mined
+                .iter()
+                .map(|summary| summary.transactions)
+                .sum::<usize>(),
+            mempool.len()
+        );
+    }
+    let Some(block) = last else {
+        return;
     };
 
-    block.mine(difficulty_target);
     block.generate_output();
+    // mine_block saved the set after every block
+    if let Some(utxo) = &utxo {
+        println!("UTXO set: {} coins", utxo.coin_count());
+    }
+    if let Some(path) = &config.raw_block {
+        if let Err(e) = block.write_raw(path) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        println!("Wrote raw block to {}", path.display());
+    }
 }
@@ -0,0 +1,123 @@
+use serde::{Deserialize, Serialize};
+
+use crate::block::double_sha256;
+
+/// Merkle tree over txids or wtxids with every level kept, so proofs can be
+/// read off without rehashing. Hashes are stored in serialized byte order.
+pub struct MerkleTree {
+    /// `levels[0]` are the leaves, the last level is the root.
+    levels: Vec<Vec<[u8; 32]>>,
+    mutated: bool,
+}
+
+/// The hashes a leaf is combined with on its way to the root.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MerkleProof {
+    /// Position of the leaf in the block.
+    pub index: usize,
+    /// One sibling per level, bottom up.
+    pub branch: Vec<[u8; 32]>,
+}
+
+impl MerkleTree {
+    /// Builds the tree from ids in display order (as txids are usually written).
+    pub fn from_ids(ids: &[String]) -> Result<MerkleTree, String> {
+        let leaves = ids
+            .iter()
+            .map(|id| id_to_hash(id))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(MerkleTree::from_hashes(leaves))
+    }
+
+    /// Builds the tree from leaves in serialized byte order. An odd node at
+    /// the end of a level is paired with itself.
+    pub fn from_hashes(leaves: Vec<[u8; 32]>) -> MerkleTree {
+        let mut levels = vec![leaves];
+        let mut mutated = false;
+        while levels.last().unwrap().len() > 1 {
+            let level = levels.last().unwrap();
+            let mut next = Vec::with_capacity(level.len().div_ceil(2));
+            for pair in level.chunks(2) {
+                let right = match pair {
+                    [_, right] => {
+                        mutated |= pair[0] == *right;
+                        right
+                    }
+                    _ => &pair[0],
+                };
+                next.push(hash_pair(&pair[0], right));
+            }
+            levels.push(next);
+        }
+        MerkleTree { levels, mutated }
+    }
+
+    /// None for an empty tree.
+    pub fn root(&self) -> Option<[u8; 32]> {
+        self.levels.last().unwrap().first().copied()
+    }
+
+    /// Root as hex in serialized byte order, as it appears in the header.
+    pub fn root_hex(&self) -> Option<String> {
+        self.root().map(hex::encode)
+    }
+
+    pub fn proof(&self, index: usize) -> Option<MerkleProof> {
+        if index >= self.levels[0].len() {
+            return None;
+        }
+        let mut branch = Vec::new();
+        let mut position = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = level.get(position ^ 1).unwrap_or(&level[position]);
+            branch.push(*sibling);
+            position /= 2;
+        }
+        Some(MerkleProof { index, branch })
+    }
+
+    /// Whether two identical hashes were paired on some level (CVE-2012-2459).
+    /// Such a tree has the same root as one with those leaves duplicated, so
+    /// a block with this root can be mutated without changing its hash.
+    pub fn is_mutated(&self) -> bool {
+        self.mutated
+    }
+}
+
+impl MerkleProof {
+    /// Root reached from `leaf` (serialized byte order) along this branch.
+    pub fn compute_root(&self, leaf: [u8; 32]) -> [u8; 32] {
+        let mut hash = leaf;
+        let mut position = self.index;
+        for sibling in &self.branch {
+            hash = if position & 1 == 1 {
+                hash_pair(sibling, &hash)
+            } else {
+                hash_pair(&hash, sibling)
+            };
+            position /= 2;
+        }
+        hash
+    }
+
+    pub fn verify(&self, leaf: [u8; 32], root: [u8; 32]) -> bool {
+        self.compute_root(leaf) == root
+    }
+}
+
+/// Display-order id (txid, wtxid, block hash) to serialized byte order.
+pub fn id_to_hash(id: &str) -> Result<[u8; 32], String> {
+    let mut hash: [u8; 32] = hex::decode(id)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| format!("Invalid id: {}", id))?;
+    hash.reverse();
+    Ok(hash)
+}
+
+pub fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut data = [0u8; 64];
+    data[..32].copy_from_slice(left);
+    data[32..].copy_from_slice(right);
+    double_sha256(&data).try_into().unwrap()
+}
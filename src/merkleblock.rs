@@ -0,0 +1,370 @@
+use crate::block::BlockHeader;
+use crate::consensus::MAX_BLOCK_WEIGHT;
+use crate::merkle::{hash_pair, id_to_hash};
+use crate::tx::{serialize_varint, ByteReader};
+
+/// Smallest possible transaction weight, bounding how many fit in a block.
+const MIN_TRANSACTION_WEIGHT: usize = 4 * 60;
+
+/// A matched leaf: its position and txid (serialized byte order).
+type Match = (usize, [u8; 32]);
+
+/// BIP37 partial merkle tree: a depth-first walk of the block's merkle tree
+/// that only descends into subtrees containing a matched transaction. Each
+/// visited node has a flag bit; every subtree that isn't descended into, and
+/// every matched leaf, contributes its hash.
+pub struct PartialMerkleTree {
+    pub transaction_count: u32,
+    /// Hashes in serialized byte order, in traversal order.
+    pub hashes: Vec<[u8; 32]>,
+    pub flags: Vec<bool>,
+}
+
+/// A `merkleblock` message: a header and the partial tree for the
+/// transactions a client asked about.
+pub struct MerkleBlock {
+    pub header: BlockHeader,
+    pub tree: PartialMerkleTree,
+}
+
+impl PartialMerkleTree {
+    /// Builds the tree for `txids` (serialized byte order), marking those
+    /// where `matches` is true.
+    pub fn build(txids: &[[u8; 32]], matches: &[bool]) -> PartialMerkleTree {
+        let mut tree = PartialMerkleTree {
+            transaction_count: txids.len() as u32,
+            hashes: Vec::new(),
+            flags: Vec::new(),
+        };
+        let height = tree.height();
+        tree.traverse_and_build(height, 0, txids, matches);
+        tree
+    }
+
+    /// Walks the tree as it was built, returning the merkle root and the
+    /// matched transactions with their positions. Fails if the encoding is
+    /// inconsistent or not minimal, or pairs identical hashes (CVE-2012-2459).
+    pub fn extract_matches(&self) -> Result<([u8; 32], Vec<Match>), String> {
+        let count = self.transaction_count as usize;
+        if count == 0 {
+            return Err("Partial merkle tree has no transactions".to_string());
+        }
+        if count > MAX_BLOCK_WEIGHT / MIN_TRANSACTION_WEIGHT {
+            return Err(format!("Too many transactions: {}", count));
+        }
+        if self.hashes.len() > count {
+            return Err("More hashes than transactions".to_string());
+        }
+        if self.flags.len() < self.hashes.len() {
+            return Err("Fewer flag bits than hashes".to_string());
+        }
+
+        let mut cursor = (0, 0);
+        let mut matches = Vec::new();
+        let root = self.traverse_and_extract(self.height(), 0, &mut cursor, &mut matches)?;
+
+        // Only the padding of the last flag byte may be left over
+        let (bits_used, hashes_used) = cursor;
+        if bits_used.div_ceil(8) != self.flags.len().div_ceil(8) {
+            return Err("Unused flag bits".to_string());
+        }
+        if hashes_used != self.hashes.len() {
+            return Err("Unused hashes".to_string());
+        }
+        Ok((root, matches))
+    }
+
+    /// `u32` transaction count, then the hashes and the flag bits packed
+    /// least significant bit first, each prefixed with a varint count.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = self.transaction_count.to_le_bytes().to_vec();
+        data.extend(serialize_varint(self.hashes.len() as u64));
+        for hash in &self.hashes {
+            data.extend(hash);
+        }
+
+        let mut flag_bytes = vec![0u8; self.flags.len().div_ceil(8)];
+        for (i, &flag) in self.flags.iter().enumerate() {
+            flag_bytes[i / 8] |= (flag as u8) << (i % 8);
+        }
+        data.extend(serialize_varint(flag_bytes.len() as u64));
+        data.extend(flag_bytes);
+        data
+    }
+
+    pub fn parse(reader: &mut ByteReader) -> Result<PartialMerkleTree, String> {
+        let transaction_count = reader.read_u32()?;
+        let hash_count = reader.read_varint()?;
+        let mut hashes = Vec::new();
+        for _ in 0..hash_count {
+            hashes.push(reader.read_bytes(32)?.try_into().unwrap());
+        }
+        let flag_count = reader.read_varint()? as usize;
+        let flags = reader
+            .read_bytes(flag_count)?
+            .iter()
+            .flat_map(|byte| (0..8).map(move |bit| byte >> bit & 1 == 1))
+            .collect();
+        Ok(PartialMerkleTree {
+            transaction_count,
+            hashes,
+            flags,
+        })
+    }
+
+    /// Number of nodes at `height` above the leaves.
+    fn width(&self, height: u32) -> usize {
+        (self.transaction_count as usize + (1 << height) - 1) >> height
+    }
+
+    fn height(&self) -> u32 {
+        let mut height = 0;
+        while self.width(height) > 1 {
+            height += 1;
+        }
+        height
+    }
+
+    fn node_hash(&self, height: u32, pos: usize, txids: &[[u8; 32]]) -> [u8; 32] {
+        if height == 0 {
+            return txids[pos];
+        }
+        let left = self.node_hash(height - 1, pos * 2, txids);
+        let right = if pos * 2 + 1 < self.width(height - 1) {
+            self.node_hash(height - 1, pos * 2 + 1, txids)
+        } else {
+            left
+        };
+        hash_pair(&left, &right)
+    }
+
+    fn traverse_and_build(
+        &mut self,
+        height: u32,
+        pos: usize,
+        txids: &[[u8; 32]],
+        matches: &[bool],
+    ) {
+        let leaves = (pos << height)..((pos + 1) << height).min(txids.len());
+        let parent_of_match = matches[leaves].iter().any(|&m| m);
+        self.flags.push(parent_of_match);
+
+        if height == 0 || !parent_of_match {
+            let hash = self.node_hash(height, pos, txids);
+            self.hashes.push(hash);
+        } else {
+            self.traverse_and_build(height - 1, pos * 2, txids, matches);
+            if pos * 2 + 1 < self.width(height - 1) {
+                self.traverse_and_build(height - 1, pos * 2 + 1, txids, matches);
+            }
+        }
+    }
+
+    /// `cursor` is (flag bits used, hashes used).
+    fn traverse_and_extract(
+        &self,
+        height: u32,
+        pos: usize,
+        cursor: &mut (usize, usize),
+        matches: &mut Vec<Match>,
+    ) -> Result<[u8; 32], String> {
+        let parent_of_match = *self.flags.get(cursor.0).ok_or("Ran out of flag bits")?;
+        cursor.0 += 1;
+
+        if height == 0 || !parent_of_match {
+            let hash = *self.hashes.get(cursor.1).ok_or("Ran out of hashes")?;
+            cursor.1 += 1;
+            if height == 0 && parent_of_match {
+                matches.push((pos, hash));
+            }
+            return Ok(hash);
+        }
+
+        let left = self.traverse_and_extract(height - 1, pos * 2, cursor, matches)?;
+        let right = if pos * 2 + 1 < self.width(height - 1) {
+            let right = self.traverse_and_extract(height - 1, pos * 2 + 1, cursor, matches)?;
+            if right == left {
+                return Err("Identical sibling hashes (CVE-2012-2459)".to_string());
+            }
+            right
+        } else {
+            left
+        };
+        Ok(hash_pair(&left, &right))
+    }
+}
+
+impl MerkleBlock {
+    /// Builds the message for `header`, whose block has `txids` (display
+    /// order), matching the transactions in `matched`.
+    pub fn new(
+        header: BlockHeader,
+        txids: &[String],
+        matched: &[String],
+    ) -> Result<MerkleBlock, String> {
+        let leaves = txids
+            .iter()
+            .map(|txid| id_to_hash(txid))
+            .collect::<Result<Vec<_>, _>>()?;
+        let matches = txids
+            .iter()
+            .map(|txid| matched.contains(txid))
+            .collect::<Vec<_>>();
+        Ok(MerkleBlock {
+            header,
+            tree: PartialMerkleTree::build(&leaves, &matches),
+        })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = self.header.serialize();
+        data.extend(self.tree.serialize());
+        data
+    }
+
+    pub fn parse(data: &[u8]) -> Result<MerkleBlock, String> {
+        let mut reader = ByteReader::new(data);
+        let header = BlockHeader::parse(&mut reader)?;
+        let tree = PartialMerkleTree::parse(&mut reader)?;
+        if !reader.is_empty() {
+            return Err("Trailing data after the partial merkle tree".to_string());
+        }
+        Ok(MerkleBlock { header, tree })
+    }
+
+    /// Checks the partial tree against the header's merkle root and returns
+    /// the matched transactions as (position, txid in display order).
+    pub fn verify(&self) -> Result<Vec<(usize, String)>, String> {
+        let (root, matches) = self.tree.extract_matches()?;
+        if hex::encode(root) != self.header.merkle_root {
+            return Err(format!(
+                "Partial tree root {} doesn't match the header merkle root {}",
+                hex::encode(root),
+                self.header.merkle_root
+            ));
+        }
+        Ok(matches
+            .into_iter()
+            .map(|(pos, mut hash)| {
+                hash.reverse();
+                (pos, hex::encode(hash))
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle::MerkleTree;
+
+    /// Block 100000's txids (display order) and merkle root (serialized order).
+    const BLOCK_100000_TXIDS: [&str; 4] = [
+        "8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87",
+        "fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4",
+        "6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4",
+        "e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d",
+    ];
+    const BLOCK_100000_ROOT: &str =
+        "6657a9252aacd5c0b2940996ecff952228c3067cc38d4885efb5a4ac4247e9f3";
+
+    fn txids(count: usize) -> Vec<[u8; 32]> {
+        (0..count)
+            .map(|i| {
+                let mut txid = [0u8; 32];
+                txid[..8].copy_from_slice(&(i as u64 + 1).to_le_bytes());
+                txid
+            })
+            .collect()
+    }
+
+    fn round_trip(tree: &PartialMerkleTree) -> PartialMerkleTree {
+        let data = tree.serialize();
+        let mut reader = ByteReader::new(&data);
+        let parsed = PartialMerkleTree::parse(&mut reader).unwrap();
+        assert!(reader.is_empty());
+        parsed
+    }
+
+    #[test]
+    fn round_trips_match_none_one_and_all() {
+        for count in [1, 2, 3, 4, 7, 8, 13] {
+            let leaves = txids(count);
+            let root = MerkleTree::from_hashes(leaves.clone()).root().unwrap();
+            let patterns = [
+                vec![false; count],
+                (0..count).map(|i| i == count / 2).collect(),
+                vec![true; count],
+            ];
+            for matches in patterns {
+                let tree = round_trip(&PartialMerkleTree::build(&leaves, &matches));
+                let (extracted_root, extracted) = tree.extract_matches().unwrap();
+                assert_eq!(extracted_root, root, "{} transactions", count);
+
+                let expected = (0..count)
+                    .filter(|&i| matches[i])
+                    .map(|i| (i, leaves[i]))
+                    .collect::<Vec<_>>();
+                assert_eq!(extracted, expected, "{} transactions", count);
+            }
+        }
+    }
+
+    #[test]
+    fn encodes_block_100000() {
+        let leaves = BLOCK_100000_TXIDS.map(|txid| id_to_hash(txid).unwrap());
+        let tree = PartialMerkleTree::build(&leaves, &[false, true, false, false]);
+
+        // Root, left pair, leaf 0, leaf 1 (matched), right pair: 1 1 0 1 0
+        let right_pair = "49aef42d78e3e9999c9e6ec9e1dddd6cb880bf3b076a03be1318ca789089308e";
+        let expected = format!(
+            "0400000003{}{}{}010b",
+            hex::encode(leaves[0]),
+            hex::encode(leaves[1]),
+            right_pair
+        );
+        assert_eq!(hex::encode(tree.serialize()), expected);
+
+        let (root, matches) = round_trip(&tree).extract_matches().unwrap();
+        assert_eq!(hex::encode(root), BLOCK_100000_ROOT);
+        assert_eq!(matches, vec![(1, leaves[1])]);
+    }
+
+    #[test]
+    fn rejects_trailing_flag_byte() {
+        let leaves = txids(5);
+        let mut data =
+            PartialMerkleTree::build(&leaves, &[true, false, false, false, false]).serialize();
+        let flag_count = data.len() - 2;
+        assert_eq!(data[flag_count], 1);
+        data[flag_count] = 2;
+        data.push(0);
+
+        let tree = PartialMerkleTree::parse(&mut ByteReader::new(&data)).unwrap();
+        assert_eq!(tree.extract_matches().unwrap_err(), "Unused flag bits");
+    }
+
+    #[test]
+    fn rejects_unused_hash() {
+        let leaves = txids(5);
+        let mut tree = PartialMerkleTree::build(&leaves, &[false, false, true, false, false]);
+        tree.hashes.push([0xab; 32]);
+        let tree = round_trip(&tree);
+        assert_eq!(tree.extract_matches().unwrap_err(), "Unused hashes");
+    }
+
+    #[test]
+    fn rejects_identical_siblings() {
+        // The last pair repeated, as in a mutated block (CVE-2012-2459)
+        let mut leaves = txids(3);
+        leaves.push(leaves[2]);
+        let tree = round_trip(&PartialMerkleTree::build(
+            &leaves,
+            &[false, false, true, false],
+        ));
+        assert_eq!(
+            tree.extract_matches().unwrap_err(),
+            "Identical sibling hashes (CVE-2012-2459)"
+        );
+    }
+}
@@ -0,0 +1,63 @@
+use crate::sighash::{taproot_key_spend_sighash, SIGHASH_DEFAULT};
+use crate::tx::Transaction;
+use crate::validate::SigVerifier;
+
+const ANNEX_TAG: u8 = 0x50;
+
+impl Transaction {
+    /// Whether the input spends a taproot output through a script path:
+    /// more than one witness element once the annex is stripped.
+    pub fn is_p2tr_script_path(&self, index: usize) -> bool {
+        let witness = self.vin[index].witness.as_deref().unwrap_or_default();
+        let has_annex = witness.len() >= 2
+            && witness
+                .last()
+                .is_some_and(|last| last.starts_with(&format!("{:02x}", ANNEX_TAG)));
+        witness.len() - has_annex as usize > 1
+    }
+
+    /// Verifies a taproot key-path spend.
+    ///
+    /// Script-path spends (more than one witness element once the annex is
+    /// stripped) are rejected since we don't execute tapscript.
+    pub fn verify_p2tr_input(&self, index: usize, verifier: &SigVerifier) -> Result<(), String> {
+        let input = &self.vin[index];
+
+        let scriptpubkey = hex::decode(&input.prevout.scriptpubkey)
+            .map_err(|_| format!("Invalid scriptpubkey: {}", input.prevout.scriptpubkey))?;
+        if scriptpubkey.len() != 34 || scriptpubkey[..2] != [0x51, 0x20] {
+            return Err("Malformed P2TR scriptpubkey".to_string());
+        }
+        if !input.scriptsig.is_empty() {
+            return Err("Native segwit input with non-empty scriptsig".to_string());
+        }
+
+        let mut witness = input
+            .witness
+            .as_deref()
+            .unwrap_or_default()
+            .iter()
+            .map(|item| hex::decode(item).map_err(|_| "Invalid witness data".to_string()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let annex = match witness.last() {
+            Some(last) if witness.len() >= 2 && last.first() == Some(&ANNEX_TAG) => witness.pop(),
+            _ => None,
+        };
+
+        let [signature] = witness.as_slice() else {
+            return Err("Taproot script-path spends are not supported".to_string());
+        };
+
+        let (sighash_type, sig) = match signature.len() {
+            64 => (SIGHASH_DEFAULT, &signature[..]),
+            65 if signature[64] != SIGHASH_DEFAULT as u8 => {
+                (signature[64] as u32, &signature[..64])
+            }
+            _ => return Err("Invalid taproot signature length".to_string()),
+        };
+
+        let sighash = taproot_key_spend_sighash(self, index, sighash_type, annex.as_deref())?;
+        verifier.verify_schnorr(&sighash, &scriptpubkey[2..], sig)
+    }
+}
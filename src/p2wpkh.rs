@@ -0,0 +1,43 @@
+use crate::sighash::segwit_v0_sighash;
+use crate::tx::Transaction;
+use crate::validate::{hash160, SigVerifier};
+
+impl Transaction {
+    /// Verifies a P2WPKH input, native or nested in P2SH.
+    ///
+    /// `pubkey_hash` is the 20 byte witness program. The witness must be
+    /// `[<sig>, <pubkey>]` and the signature commits to the BIP143 sighash with
+    /// the implied `OP_DUP OP_HASH160 <pkh> OP_EQUALVERIFY OP_CHECKSIG` script code.
+    pub fn verify_p2wpkh_input(
+        &self,
+        index: usize,
+        pubkey_hash: &[u8],
+        verifier: &SigVerifier,
+    ) -> Result<(), String> {
+        let input = &self.vin[index];
+        let witness = input.witness.as_deref().unwrap_or_default();
+        let [signature, pubkey] = witness else {
+            return Err("P2WPKH witness must contain a signature and a public key".to_string());
+        };
+        let signature = hex::decode(signature).map_err(|_| "Invalid witness data".to_string())?;
+        let pubkey = hex::decode(pubkey).map_err(|_| "Invalid witness data".to_string())?;
+
+        if hash160(&pubkey) != pubkey_hash {
+            return Err("Public key hash mismatch".to_string());
+        }
+
+        let mut script_code = vec![0x76, 0xa9, 0x14];
+        script_code.extend(pubkey_hash);
+        script_code.extend([0x88, 0xac]);
+
+        let (&sighash_type, der) = signature.split_last().ok_or("Empty signature")?;
+        let sighash = segwit_v0_sighash(
+            self,
+            index,
+            &script_code,
+            input.prevout.value,
+            sighash_type as u32,
+        )?;
+        verifier.verify_ecdsa(&sighash, &pubkey, der)
+    }
+}
@@ -0,0 +1,420 @@
+use num_bigint::BigUint;
+
+use crate::block::BlockHeader;
+use crate::consensus::ConsensusParams;
+
+/// Compact bits of difficulty 1 (the genesis block target).
+const DIFFICULTY_1_BITS: u32 = 0x1d00ffff;
+
+/// Expands compact nBits into the 256-bit target (`arith_uint256::SetCompact`).
+/// Targets that would be negative, overflow 256 bits or be zero are rejected,
+/// as `CheckProofOfWork` does.
+pub fn bits_to_target(bits: u32) -> Result<BigUint, String> {
+    let size = bits >> 24;
+    let mut word = bits & 0x007fffff;
+    // The sign and overflow checks see the mantissa after it is shifted
+    if size <= 3 {
+        word >>= 8 * (3 - size);
+    }
+
+    if word != 0 && bits & 0x00800000 != 0 {
+        return Err(format!("Negative target in bits {:#010x}", bits));
+    }
+    if word != 0 && (size > 34 || (word > 0xff && size > 33) || (word > 0xffff && size > 32)) {
+        return Err(format!("Target overflows 256 bits in bits {:#010x}", bits));
+    }
+
+    let target = if size <= 3 {
+        BigUint::from(word)
+    } else {
+        BigUint::from(word) << (8 * (size - 3))
+    };
+    if target.bits() == 0 {
+        return Err(format!("Zero target in bits {:#010x}", bits));
+    }
+    Ok(target)
+}
+
+/// Compresses a target into nBits (`arith_uint256::GetCompact`), rounding
+/// down to the 3 most significant bytes. The mantissa is kept below 0x800000
+/// so the result never reads as negative.
+pub fn target_to_bits(target: &BigUint) -> u32 {
+    let mut size = target.bits().div_ceil(8) as u32;
+    let mut compact = if size <= 3 {
+        low_u32(target) << (8 * (3 - size))
+    } else {
+        low_u32(&(target >> (8 * (size - 3))))
+    };
+    if compact & 0x00800000 != 0 {
+        compact >>= 8;
+        size += 1;
+    }
+    compact | (size << 24)
+}
+
+fn low_u32(n: &BigUint) -> u32 {
+    n.iter_u32_digits().next().unwrap_or(0)
+}
+
+/// The target as 32 big-endian bytes, comparable with a reversed block hash.
+pub fn target_to_bytes(target: &BigUint) -> [u8; 32] {
+    let bytes = target.to_bytes_be();
+    let mut result = [0u8; 32];
+    result[32 - bytes.len()..].copy_from_slice(&bytes);
+    result
+}
+
+/// How many times harder `bits` is than difficulty 1, computed in floating
+/// point like `GetDifficulty` so very small targets don't lose precision.
+pub fn difficulty(bits: u32) -> f64 {
+    let mantissa = bits & 0x00ffffff;
+    if mantissa == 0 {
+        return 0.0;
+    }
+    let mut shift = (bits >> 24) as i32;
+    let mut diff = (DIFFICULTY_1_BITS & 0x00ffffff) as f64 / mantissa as f64;
+    while shift < 29 {
+        diff *= 256.0;
+        shift += 1;
+    }
+    while shift > 29 {
+        diff /= 256.0;
+        shift -= 1;
+    }
+    diff
+}
+
+/// Expected number of hashes to find a block at `bits`: 2^256 / (target + 1),
+/// as in `GetBlockProof`. A chain's chainwork is the sum over its headers.
+pub fn block_work(bits: u32) -> Result<BigUint, String> {
+    let target = bits_to_target(bits)?;
+    Ok((BigUint::from(1u32) << 256u32) / (target + 1u32))
+}
+
+/// Whether a block hash in display order, read as a big-endian number,
+/// is at or below `target`.
+pub fn hash_meets_target(hash: &str, target: &BigUint) -> bool {
+    match hex::decode(hash) {
+        Ok(bytes) => BigUint::from_bytes_be(&bytes) <= *target,
+        Err(_) => false,
+    }
+}
+
+/// Bits required for the block after `headers` (`GetNextWorkRequired`).
+///
+/// `headers` ends with the tip at `tip_height`; an empty slice means the next
+/// block is the genesis block. At a retarget it must reach back to the first
+/// block of the ending period. Testnet's walk back past min-difficulty blocks
+/// stops at the oldest header given. `next_time` is the new block's
+/// timestamp, which only matters for testnet's 20-minute rule.
+pub fn next_work_required(
+    headers: &[BlockHeader],
+    tip_height: u32,
+    next_time: u32,
+    params: &ConsensusParams,
+) -> Result<u32, String> {
+    let pow_limit_bits = target_to_bits(&params.pow_limit);
+    let Some(tip) = headers.last() else {
+        return Ok(pow_limit_bits);
+    };
+    let interval = params.difficulty_adjustment_interval();
+    let ancestor = |height: u32| {
+        let back = (tip_height - height) as usize;
+        headers.len().checked_sub(back + 1).map(|i| &headers[i])
+    };
+
+    if !(tip_height + 1).is_multiple_of(interval) {
+        if params.allow_min_difficulty_blocks {
+            if next_time > tip.time + params.pow_target_spacing * 2 {
+                return Ok(pow_limit_bits);
+            }
+            // Otherwise use the last bits that weren't a min-difficulty exception
+            let mut height = tip_height;
+            let mut header = tip;
+            while height > 0 && !height.is_multiple_of(interval) && header.bits == pow_limit_bits {
+                let Some(parent) = ancestor(height - 1) else {
+                    break;
+                };
+                height -= 1;
+                header = parent;
+            }
+            return Ok(header.bits);
+        }
+        return Ok(tip.bits);
+    }
+
+    // The period's first block is interval - 1 back, so the timespan covers
+    // 2015 intervals rather than 2016 (a long-standing off-by-one).
+    let first_height = tip_height + 1 - interval;
+    let first = ancestor(first_height).ok_or_else(|| {
+        format!(
+            "Retargeting at height {} needs the period's first block at height {}, \
+             but the headers only go back to height {}",
+            tip_height + 1,
+            first_height,
+            tip_height + 1 - headers.len() as u32
+        )
+    })?;
+    retarget(tip, first.time, params)
+}
+
+/// Scales the tip's target by how long the period took, clamped to a factor
+/// of 4 either way and capped at the pow limit (`CalculateNextWorkRequired`).
+fn retarget(tip: &BlockHeader, first_time: u32, params: &ConsensusParams) -> Result<u32, String> {
+    if params.no_retargeting {
+        return Ok(tip.bits);
+    }
+    let timespan = params.pow_target_timespan as i64;
+    let actual = (tip.time as i64 - first_time as i64).clamp(timespan / 4, timespan * 4);
+
+    let mut target = bits_to_target(tip.bits)? * actual as u64 / timespan as u64;
+    if target > params.pow_limit {
+        target = params.pow_limit.clone();
+    }
+    Ok(target_to_bits(&target))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::CHALLENGE_BITS;
+    use crate::network::Network;
+
+    const TESTNET_LIMIT_BITS: u32 = 0x1d00ffff;
+
+    fn target(hex_target: &str) -> BigUint {
+        BigUint::parse_bytes(hex_target.as_bytes(), 16).unwrap()
+    }
+
+    // Core's arith_uint256 SetCompact/GetCompact vectors
+
+    #[test]
+    fn zero_targets_are_rejected() {
+        for bits in [
+            0x00000000, 0x00123456, 0x01003456, 0x02000056, 0x03000000, 0x04000000, 0x00923456,
+            0x01803456, 0x02800056, 0x03800000, 0x04800000,
+        ] {
+            let result = bits_to_target(bits);
+            assert!(
+                result.unwrap_err().starts_with("Zero target"),
+                "{:#010x}",
+                bits
+            );
+        }
+    }
+
+    #[test]
+    fn compact_round_trips() {
+        for (bits, hex_target, compact) in [
+            (0x01123456, "12", 0x01120000),
+            (0x02123456, "1234", 0x02123400),
+            (0x03123456, "123456", 0x03123456),
+            (0x04123456, "12345600", 0x04123456),
+            (0x05009234, "92340000", 0x05009234),
+            (
+                0x20123456,
+                "1234560000000000000000000000000000000000000000000000000000000000",
+                0x20123456,
+            ),
+            (
+                0x1d00ffff,
+                "ffff0000000000000000000000000000000000000000000000000000",
+                0x1d00ffff,
+            ),
+        ] {
+            assert_eq!(
+                bits_to_target(bits),
+                Ok(target(hex_target)),
+                "{:#010x}",
+                bits
+            );
+            assert_eq!(
+                target_to_bits(&target(hex_target)),
+                compact,
+                "{:#010x}",
+                bits
+            );
+        }
+        // A mantissa with the sign bit set moves up a byte
+        assert_eq!(target_to_bits(&target("80")), 0x02008000);
+    }
+
+    #[test]
+    fn negative_and_overflowing_bits_are_rejected() {
+        for bits in [0x01fedcba, 0x04923456] {
+            let result = bits_to_target(bits);
+            assert!(
+                result.unwrap_err().starts_with("Negative target"),
+                "{:#010x}",
+                bits
+            );
+        }
+        let result = bits_to_target(0xff123456);
+        assert!(result.unwrap_err().starts_with("Target overflows"));
+    }
+
+    #[test]
+    fn difficulty_matches_get_difficulty() {
+        // Core's blockchain_tests, to six decimals
+        for (bits, expected) in [
+            (0x1f111111, 0.000001),
+            (0x1ef88f6f, 0.000016),
+            (0x1df88f6f, 0.004023),
+            (0x1cf88f6f, 1.029916),
+            (0x1d00ffff, 1.0),
+        ] {
+            assert!(
+                (difficulty(bits) - expected).abs() < 0.0000005,
+                "{:#010x}",
+                bits
+            );
+        }
+        let big = difficulty(0x12345678);
+        assert!((big / 5913134931067755359633408.0 - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn block_work_is_2_256_over_target_plus_one() {
+        assert_eq!(block_work(0x1d00ffff), Ok(BigUint::from(0x100010001u64)));
+        assert_eq!(block_work(0x207fffff), Ok(BigUint::from(2u32)));
+        assert_eq!(block_work(CHALLENGE_BITS), Ok(BigUint::from(0x10001u32)));
+    }
+
+    #[test]
+    fn challenge_bits_are_the_readme_target() {
+        let readme = target("0000ffff00000000000000000000000000000000000000000000000000000000");
+        assert_eq!(bits_to_target(CHALLENGE_BITS), Ok(readme.clone()));
+        assert_eq!(target_to_bits(&readme), CHALLENGE_BITS);
+    }
+
+    fn header(time: u32, bits: u32) -> BlockHeader {
+        BlockHeader {
+            version: 0x20000000,
+            previous_block_hash: "00".repeat(32),
+            merkle_root: "00".repeat(32),
+            time,
+            bits,
+            nonce: 0,
+        }
+    }
+
+    /// A full retarget period ending at `tip_height`: the first block at
+    /// `first_time`, the tip at `tip_time`, all with `bits`.
+    fn period(first_time: u32, tip_time: u32, bits: u32) -> Vec<BlockHeader> {
+        let mut headers = vec![header(first_time, bits)];
+        headers.extend((1..2015).map(|i| header(first_time + i * 600, bits)));
+        headers.push(header(tip_time, bits));
+        headers
+    }
+
+    // Core's pow_tests vectors for CalculateNextWorkRequired
+
+    #[test]
+    fn retarget_scales_by_the_period_length() {
+        let params = ConsensusParams::for_network(Network::Mainnet);
+        let headers = period(1261130161, 1262152739, 0x1d00ffff);
+        assert_eq!(
+            next_work_required(&headers, 32255, 0, &params),
+            Ok(0x1d00d86a)
+        );
+    }
+
+    #[test]
+    fn retarget_is_capped_at_the_pow_limit() {
+        let params = ConsensusParams::for_network(Network::Mainnet);
+        let headers = period(1231006505, 1233061996, 0x1d00ffff);
+        assert_eq!(
+            next_work_required(&headers, 2015, 0, &params),
+            Ok(0x1d00ffff)
+        );
+    }
+
+    #[test]
+    fn retarget_is_clamped_to_a_factor_of_four() {
+        let params = ConsensusParams::for_network(Network::Mainnet);
+        // Under a quarter of two weeks
+        let headers = period(1279008237, 1279297671, 0x1c05a3f4);
+        assert_eq!(
+            next_work_required(&headers, 68543, 0, &params),
+            Ok(0x1c0168fd)
+        );
+        // Over four times two weeks
+        let headers = period(1263163443, 1269211443, 0x1c387f6f);
+        assert_eq!(
+            next_work_required(&headers, 46367, 0, &params),
+            Ok(0x1d00e1fd)
+        );
+    }
+
+    #[test]
+    fn retarget_measures_2015_intervals() {
+        let params = ConsensusParams::for_network(Network::Mainnet);
+        let mut headers = period(1279008237, 1279297671, 0x1c05a3f4);
+        // A header before the period's first block doesn't count...
+        headers.insert(0, header(0, 0x1c05a3f4));
+        assert_eq!(
+            next_work_required(&headers, 68543, 0, &params),
+            Ok(0x1c0168fd)
+        );
+        // ...and the period's first block is needed
+        let result = next_work_required(&headers[2..], 68543, 0, &params);
+        assert_eq!(
+            result,
+            Err(
+                "Retargeting at height 68544 needs the period's first block at height 66528, \
+                 but the headers only go back to height 66529"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn retarget_needs_the_first_block_of_the_period() {
+        // A mainnet chain rooted at the default height reaches the 840672
+        // retarget without the block at 838656
+        let params = ConsensusParams::for_network(Network::Mainnet);
+        let headers = (0..673)
+            .map(|i| header(1713571767 + i * 600, 0x17034219))
+            .collect::<Vec<_>>();
+        let result = next_work_required(&headers, 840671, 0, &params);
+        assert!(result.unwrap_err().contains("first block at height 838656"));
+    }
+
+    #[test]
+    fn testnet_allows_min_difficulty_after_20_minutes() {
+        let params = ConsensusParams::for_network(Network::Testnet);
+        let headers = vec![header(1000, 0x1c00ffff), header(1600, 0x1c00ffff)];
+        assert_eq!(
+            next_work_required(&headers, 101, 2800, &params),
+            Ok(0x1c00ffff)
+        );
+        assert_eq!(
+            next_work_required(&headers, 101, 2801, &params),
+            Ok(TESTNET_LIMIT_BITS)
+        );
+    }
+
+    #[test]
+    fn testnet_walks_back_past_min_difficulty_blocks() {
+        let params = ConsensusParams::for_network(Network::Testnet);
+        let headers = vec![
+            header(1000, 0x1c00ffff),
+            header(3000, TESTNET_LIMIT_BITS),
+            header(5000, TESTNET_LIMIT_BITS),
+        ];
+        assert_eq!(
+            next_work_required(&headers, 102, 5600, &params),
+            Ok(0x1c00ffff)
+        );
+        // The walk stops at a retarget boundary...
+        assert_eq!(
+            next_work_required(&headers, 2017, 5600, &params),
+            Ok(TESTNET_LIMIT_BITS)
+        );
+        // ...or at the oldest header given
+        assert_eq!(
+            next_work_required(&headers[1..], 102, 5600, &params),
+            Ok(TESTNET_LIMIT_BITS)
+        );
+    }
+}
@@ -0,0 +1,804 @@
+use crate::address::{script_to_address, witness_program};
+use crate::network::Network;
+use crate::tx::{Output, PrevOut, Transaction};
+
+const OPCODE_NAMES: [&str; 256] = {
+    let mut names = ["OP_PUSHBYTES"; 256];
+    names[0x00] = "OP_0";
+    names[0x4c] = "OP_PUSHDATA1";
+    names[0x4d] = "OP_PUSHDATA2";
+    names[0x4e] = "OP_PUSHDATA4";
+    names[0x4f] = "OP_PUSHNUM_NEG1";
+    names[0x50] = "OP_RESERVED";
+    names[0x51] = "OP_PUSHNUM_1";
+    names[0x52] = "OP_PUSHNUM_2";
+    names[0x53] = "OP_PUSHNUM_3";
+    names[0x54] = "OP_PUSHNUM_4";
+    names[0x55] = "OP_PUSHNUM_5";
+    names[0x56] = "OP_PUSHNUM_6";
+    names[0x57] = "OP_PUSHNUM_7";
+    names[0x58] = "OP_PUSHNUM_8";
+    names[0x59] = "OP_PUSHNUM_9";
+    names[0x5a] = "OP_PUSHNUM_10";
+    names[0x5b] = "OP_PUSHNUM_11";
+    names[0x5c] = "OP_PUSHNUM_12";
+    names[0x5d] = "OP_PUSHNUM_13";
+    names[0x5e] = "OP_PUSHNUM_14";
+    names[0x5f] = "OP_PUSHNUM_15";
+    names[0x60] = "OP_PUSHNUM_16";
+    names[0x61] = "OP_NOP";
+    names[0x62] = "OP_VER";
+    names[0x63] = "OP_IF";
+    names[0x64] = "OP_NOTIF";
+    names[0x65] = "OP_VERIF";
+    names[0x66] = "OP_VERNOTIF";
+    names[0x67] = "OP_ELSE";
+    names[0x68] = "OP_ENDIF";
+    names[0x69] = "OP_VERIFY";
+    names[0x6a] = "OP_RETURN";
+    names[0x6b] = "OP_TOALTSTACK";
+    names[0x6c] = "OP_FROMALTSTACK";
+    names[0x6d] = "OP_2DROP";
+    names[0x6e] = "OP_2DUP";
+    names[0x6f] = "OP_3DUP";
+    names[0x70] = "OP_2OVER";
+    names[0x71] = "OP_2ROT";
+    names[0x72] = "OP_2SWAP";
+    names[0x73] = "OP_IFDUP";
+    names[0x74] = "OP_DEPTH";
+    names[0x75] = "OP_DROP";
+    names[0x76] = "OP_DUP";
+    names[0x77] = "OP_NIP";
+    names[0x78] = "OP_OVER";
+    names[0x79] = "OP_PICK";
+    names[0x7a] = "OP_ROLL";
+    names[0x7b] = "OP_ROT";
+    names[0x7c] = "OP_SWAP";
+    names[0x7d] = "OP_TUCK";
+    names[0x7e] = "OP_CAT";
+    names[0x7f] = "OP_SUBSTR";
+    names[0x80] = "OP_LEFT";
+    names[0x81] = "OP_RIGHT";
+    names[0x82] = "OP_SIZE";
+    names[0x83] = "OP_INVERT";
+    names[0x84] = "OP_AND";
+    names[0x85] = "OP_OR";
+    names[0x86] = "OP_XOR";
+    names[0x87] = "OP_EQUAL";
+    names[0x88] = "OP_EQUALVERIFY";
+    names[0x89] = "OP_RESERVED1";
+    names[0x8a] = "OP_RESERVED2";
+    names[0x8b] = "OP_1ADD";
+    names[0x8c] = "OP_1SUB";
+    names[0x8d] = "OP_2MUL";
+    names[0x8e] = "OP_2DIV";
+    names[0x8f] = "OP_NEGATE";
+    names[0x90] = "OP_ABS";
+    names[0x91] = "OP_NOT";
+    names[0x92] = "OP_0NOTEQUAL";
+    names[0x93] = "OP_ADD";
+    names[0x94] = "OP_SUB";
+    names[0x95] = "OP_MUL";
+    names[0x96] = "OP_DIV";
+    names[0x97] = "OP_MOD";
+    names[0x98] = "OP_LSHIFT";
+    names[0x99] = "OP_RSHIFT";
+    names[0x9a] = "OP_BOOLAND";
+    names[0x9b] = "OP_BOOLOR";
+    names[0x9c] = "OP_NUMEQUAL";
+    names[0x9d] = "OP_NUMEQUALVERIFY";
+    names[0x9e] = "OP_NUMNOTEQUAL";
+    names[0x9f] = "OP_LESSTHAN";
+    names[0xa0] = "OP_GREATERTHAN";
+    names[0xa1] = "OP_LESSTHANOREQUAL";
+    names[0xa2] = "OP_GREATERTHANOREQUAL";
+    names[0xa3] = "OP_MIN";
+    names[0xa4] = "OP_MAX";
+    names[0xa5] = "OP_WITHIN";
+    names[0xa6] = "OP_RIPEMD160";
+    names[0xa7] = "OP_SHA1";
+    names[0xa8] = "OP_SHA256";
+    names[0xa9] = "OP_HASH160";
+    names[0xaa] = "OP_HASH256";
+    names[0xab] = "OP_CODESEPARATOR";
+    names[0xac] = "OP_CHECKSIG";
+    names[0xad] = "OP_CHECKSIGVERIFY";
+    names[0xae] = "OP_CHECKMULTISIG";
+    names[0xaf] = "OP_CHECKMULTISIGVERIFY";
+    names[0xb0] = "OP_NOP1";
+    names[0xb1] = "OP_CLTV";
+    names[0xb2] = "OP_CSV";
+    names[0xb3] = "OP_NOP4";
+    names[0xb4] = "OP_NOP5";
+    names[0xb5] = "OP_NOP6";
+    names[0xb6] = "OP_NOP7";
+    names[0xb7] = "OP_NOP8";
+    names[0xb8] = "OP_NOP9";
+    names[0xb9] = "OP_NOP10";
+    names[0xba] = "OP_CHECKSIGADD";
+    names[0xff] = "OP_INVALIDOPCODE";
+    names
+};
+
+pub const OP_RETURN: u8 = 0x6a;
+pub const OP_CHECKSIG: u8 = 0xac;
+pub const OP_CHECKSIGVERIFY: u8 = 0xad;
+pub const OP_CHECKMULTISIG: u8 = 0xae;
+pub const OP_CHECKMULTISIGVERIFY: u8 = 0xaf;
+
+/// Scripts longer than this can never be spent.
+const MAX_SCRIPT_SIZE: usize = 10_000;
+
+/// Sigops charged for a CHECKMULTISIG whose key count isn't known.
+const MAX_PUBKEYS_PER_MULTISIG: usize = 20;
+
+/// A single parsed script element.
+pub enum Instruction<'a> {
+    /// A data push, including the `OP_0` empty push.
+    Push(&'a [u8]),
+    Op(u8),
+}
+
+/// Splits a script into instructions, failing if a push runs past the end.
+pub fn instructions(script: &[u8]) -> Result<Vec<Instruction<'_>>, String> {
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < script.len() {
+        let opcode = script[i];
+        i += 1;
+        let len = match opcode {
+            0x00..=0x4b => opcode as usize,
+            0x4c => read_push_len(script, &mut i, 1)?,
+            0x4d => read_push_len(script, &mut i, 2)?,
+            0x4e => read_push_len(script, &mut i, 4)?,
+            _ => {
+                result.push(Instruction::Op(opcode));
+                continue;
+            }
+        };
+        let data = script
+            .get(i..i + len)
+            .ok_or("Push past the end of the script")?;
+        result.push(Instruction::Push(data));
+        i += len;
+    }
+    Ok(result)
+}
+
+fn read_push_len(script: &[u8], i: &mut usize, width: usize) -> Result<usize, String> {
+    let bytes = script.get(*i..*i + width).ok_or("Truncated push length")?;
+    *i += width;
+    Ok(bytes
+        .iter()
+        .rev()
+        .fold(0usize, |acc, &b| (acc << 8) | b as usize))
+}
+
+/// Encodes `data` as the smallest push opcode that fits it.
+pub fn push_data(data: &[u8]) -> Vec<u8> {
+    let mut script = match data.len() {
+        0..=0x4b => vec![data.len() as u8],
+        0x4c..=0xff => vec![0x4c, data.len() as u8],
+        0x100..=0xffff => {
+            let mut op = vec![0x4d];
+            op.extend((data.len() as u16).to_le_bytes());
+            op
+        }
+        _ => {
+            let mut op = vec![0x4e];
+            op.extend((data.len() as u32).to_le_bytes());
+            op
+        }
+    };
+    script.extend(data);
+    script
+}
+
+/// Minimal little-endian sign-magnitude encoding used for script numbers.
+pub fn script_num(n: i64) -> Vec<u8> {
+    let mut result = Vec::new();
+    let negative = n < 0;
+    let mut abs = n.unsigned_abs();
+    while abs > 0 {
+        result.push((abs & 0xff) as u8);
+        abs >>= 8;
+    }
+    // The top bit is the sign, so add a byte if the magnitude already uses it
+    if let Some(last) = result.last_mut() {
+        if *last & 0x80 != 0 {
+            result.push(if negative { 0x80 } else { 0x00 });
+        } else if negative {
+            *last |= 0x80;
+        }
+    }
+    result
+}
+
+/// Pushes a number the way `CScript() << n` does: OP_0, OP_1NEGATE and
+/// OP_1..OP_16 for small values, a minimal script number push otherwise.
+pub fn push_int(n: i64) -> Vec<u8> {
+    match n {
+        0 => vec![0x00],
+        -1 => vec![0x4f],
+        1..=16 => vec![0x50 + n as u8],
+        _ => push_data(&script_num(n)),
+    }
+}
+
+/// Inverse of `script_num`: little-endian with the sign in the top bit.
+pub fn decode_script_num(data: &[u8]) -> i64 {
+    let Some(&last) = data.last() else {
+        return 0;
+    };
+    let mut value = 0i64;
+    for (i, &byte) in data.iter().enumerate() {
+        value |= (byte as i64) << (8 * i);
+    }
+    if last & 0x80 != 0 {
+        -(value & !(0x80i64 << (8 * (data.len() - 1))))
+    } else {
+        value
+    }
+}
+
+/// Splits a script into its data pushes, failing on any non-push opcode.
+pub fn parse_pushes(script: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    instructions(script)?
+        .into_iter()
+        .map(|ins| match ins {
+            Instruction::Push(data) => Ok(data.to_vec()),
+            Instruction::Op(opcode) => Err(format!("Non-push opcode {:#04x} in scriptsig", opcode)),
+        })
+        .collect()
+}
+
+pub fn opcode_name(opcode: u8) -> String {
+    match opcode {
+        0x01..=0x4b => format!("OP_PUSHBYTES_{}", opcode),
+        0xbb..=0xfe => format!("OP_RETURN_{}", opcode),
+        _ => OPCODE_NAMES[opcode as usize].to_string(),
+    }
+}
+
+/// Disassembles a script into the asm format used by Esplora, e.g.
+/// `OP_DUP OP_HASH160 OP_PUSHBYTES_20 <hex> OP_EQUALVERIFY OP_CHECKSIG`.
+pub fn disassemble(script: &[u8]) -> String {
+    let mut parts = Vec::new();
+    let mut i = 0;
+    while i < script.len() {
+        let opcode = script[i];
+        i += 1;
+        parts.push(opcode_name(opcode));
+
+        let len = match opcode {
+            0x01..=0x4b => opcode as usize,
+            0x4c..=0x4e => {
+                let width = 1 << (opcode - 0x4c);
+                match read_push_len(script, &mut i, width) {
+                    Ok(len) => len,
+                    Err(_) => {
+                        parts.push("<unexpected end>".to_string());
+                        break;
+                    }
+                }
+            }
+            _ => continue,
+        };
+        match script.get(i..i + len) {
+            Some(data) => parts.push(hex::encode(data)),
+            None => {
+                parts.push("<push past end>".to_string());
+                break;
+            }
+        }
+        i += len;
+    }
+    parts.join(" ")
+}
+
+/// Disassembles a script like Core's `ScriptToAsmStr`, e.g.
+/// `OP_DUP OP_HASH160 <hex> OP_EQUALVERIFY OP_CHECKSIG`. Pushes of up to 4
+/// bytes are shown as script numbers and OP_1NEGATE..OP_16 as `-1`..`16`.
+/// With `decode_sighash` (used for scriptSigs) a strict DER signature ends
+/// with its sighash type, e.g. `<hex>[ALL]`, instead of the last byte.
+pub fn core_asm(script: &[u8], decode_sighash: bool) -> String {
+    let unspendable = script.first() == Some(&OP_RETURN) || script.len() > MAX_SCRIPT_SIZE;
+    let mut parts = Vec::new();
+    let mut i = 0;
+    while i < script.len() {
+        let opcode = script[i];
+        i += 1;
+        let len = match opcode {
+            0x00..=0x4b => opcode as usize,
+            0x4c..=0x4e => match read_push_len(script, &mut i, 1 << (opcode - 0x4c)) {
+                Ok(len) => len,
+                Err(_) => {
+                    parts.push("[error]".to_string());
+                    break;
+                }
+            },
+            0x4f => {
+                parts.push("-1".to_string());
+                continue;
+            }
+            0x51..=0x60 => {
+                parts.push((opcode - 0x50).to_string());
+                continue;
+            }
+            0xb1 => {
+                parts.push("OP_CHECKLOCKTIMEVERIFY".to_string());
+                continue;
+            }
+            0xb2 => {
+                parts.push("OP_CHECKSEQUENCEVERIFY".to_string());
+                continue;
+            }
+            0xbb..=0xfe => {
+                parts.push("OP_UNKNOWN".to_string());
+                continue;
+            }
+            _ => {
+                parts.push(OPCODE_NAMES[opcode as usize].to_string());
+                continue;
+            }
+        };
+        let Some(data) = script.get(i..i + len) else {
+            parts.push("[error]".to_string());
+            break;
+        };
+        i += len;
+
+        let sighash_name = match data.split_last() {
+            Some((&sighash_type, _)) if decode_sighash && !unspendable => {
+                sighash_type_name(sighash_type).filter(|_| is_strict_der_signature(data))
+            }
+            _ => None,
+        };
+        parts.push(match sighash_name {
+            _ if data.len() <= 4 => read_script_num(data).to_string(),
+            Some(name) => format!("{}[{}]", hex::encode(&data[..data.len() - 1]), name),
+            None => hex::encode(data),
+        });
+    }
+    parts.join(" ")
+}
+
+/// Reads a push as a script number (see `script_num`), without requiring
+/// the minimal encoding.
+fn read_script_num(data: &[u8]) -> i64 {
+    let Some(&last) = data.last() else {
+        return 0;
+    };
+    let value = data
+        .iter()
+        .rev()
+        .fold(0i64, |acc, &b| (acc << 8) | b as i64);
+    if last & 0x80 != 0 {
+        -(value & !(0x80i64 << (8 * (data.len() - 1))))
+    } else {
+        value
+    }
+}
+
+fn sighash_type_name(sighash_type: u8) -> Option<&'static str> {
+    match sighash_type {
+        0x01 => Some("ALL"),
+        0x02 => Some("NONE"),
+        0x03 => Some("SINGLE"),
+        0x81 => Some("ALL|ANYONECANPAY"),
+        0x82 => Some("NONE|ANYONECANPAY"),
+        0x83 => Some("SINGLE|ANYONECANPAY"),
+        _ => None,
+    }
+}
+
+/// Whether `sig` (with its sighash byte) is strict DER, as in BIP66's
+/// `IsValidSignatureEncoding`.
+fn is_strict_der_signature(sig: &[u8]) -> bool {
+    let len = sig.len();
+    if !(9..=73).contains(&len) || sig[0] != 0x30 || sig[1] as usize != len - 3 {
+        return false;
+    }
+    let len_r = sig[3] as usize;
+    if 5 + len_r >= len {
+        return false;
+    }
+    let len_s = sig[5 + len_r] as usize;
+    if len_r + len_s + 7 != len {
+        return false;
+    }
+    // Both integers are present, positive and minimally encoded
+    let integer_ok = |start: usize, int_len: usize| {
+        sig[start - 2] == 0x02
+            && int_len != 0
+            && sig[start] & 0x80 == 0
+            && !(int_len > 1 && sig[start] == 0 && sig[start + 1] & 0x80 == 0)
+    };
+    integer_ok(4, len_r) && integer_ok(len_r + 6, len_s)
+}
+
+/// Counts signature operations like `CScript::GetSigOpCount`. CHECKSIG and
+/// CHECKSIGVERIFY count one; CHECKMULTISIG(VERIFY) counts 20, or with
+/// `accurate` the key count of a directly preceding OP_1..OP_16. Counting
+/// stops at a push that runs past the end.
+pub fn sigop_count(script: &[u8], accurate: bool) -> usize {
+    let mut count = 0;
+    let mut last_opcode = 0xff;
+    let mut i = 0;
+    while i < script.len() {
+        let opcode = script[i];
+        i += 1;
+        let len = match opcode {
+            0x01..=0x4b => opcode as usize,
+            0x4c..=0x4e => match read_push_len(script, &mut i, 1 << (opcode - 0x4c)) {
+                Ok(len) => len,
+                Err(_) => break,
+            },
+            _ => 0,
+        };
+        if i + len > script.len() {
+            break;
+        }
+        i += len;
+
+        match opcode {
+            OP_CHECKSIG | OP_CHECKSIGVERIFY => count += 1,
+            OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                count += match last_opcode {
+                    0x51..=0x60 if accurate => (last_opcode - 0x50) as usize,
+                    _ => MAX_PUBKEYS_PER_MULTISIG,
+                }
+            }
+            _ => {}
+        }
+        last_opcode = opcode;
+    }
+    count
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptType {
+    Empty,
+    P2pk,
+    P2pkh,
+    P2sh,
+    V0P2wpkh,
+    V0P2wsh,
+    V1P2tr,
+    OpReturn,
+    Multisig,
+    Unknown,
+}
+
+impl ScriptType {
+    /// Classifies a scriptpubkey by its exact template.
+    pub fn classify(script: &[u8]) -> ScriptType {
+        match script {
+            [] => ScriptType::Empty,
+            [OP_RETURN, ..] => ScriptType::OpReturn,
+            [0x21, key @ .., 0xac] if key.len() == 33 => ScriptType::P2pk,
+            [0x41, key @ .., 0xac] if key.len() == 65 => ScriptType::P2pk,
+            [0x76, 0xa9, 0x14, hash @ .., 0x88, 0xac] if hash.len() == 20 => ScriptType::P2pkh,
+            [0xa9, 0x14, hash @ .., 0x87] if hash.len() == 20 => ScriptType::P2sh,
+            [0x00, 0x14, program @ ..] if program.len() == 20 => ScriptType::V0P2wpkh,
+            [0x00, 0x20, program @ ..] if program.len() == 32 => ScriptType::V0P2wsh,
+            [0x51, 0x20, program @ ..] if program.len() == 32 => ScriptType::V1P2tr,
+            _ if is_multisig(script) => ScriptType::Multisig,
+            _ => ScriptType::Unknown,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ScriptType::Empty => "empty",
+            ScriptType::P2pk => "p2pk",
+            ScriptType::P2pkh => "p2pkh",
+            ScriptType::P2sh => "p2sh",
+            ScriptType::V0P2wpkh => "v0_p2wpkh",
+            ScriptType::V0P2wsh => "v0_p2wsh",
+            ScriptType::V1P2tr => "v1_p2tr",
+            ScriptType::OpReturn => "op_return",
+            ScriptType::Multisig => "multisig",
+            ScriptType::Unknown => "unknown",
+        }
+    }
+
+    /// Type name Bitcoin Core's RPCs report for the script.
+    pub fn core_name(self) -> &'static str {
+        match self {
+            ScriptType::P2pk => "pubkey",
+            ScriptType::P2pkh => "pubkeyhash",
+            ScriptType::P2sh => "scripthash",
+            ScriptType::V0P2wpkh => "witness_v0_keyhash",
+            ScriptType::V0P2wsh => "witness_v0_scripthash",
+            ScriptType::V1P2tr => "witness_v1_taproot",
+            ScriptType::OpReturn => "nulldata",
+            ScriptType::Multisig => "multisig",
+            ScriptType::Empty | ScriptType::Unknown => "nonstandard",
+        }
+    }
+
+    /// Value Esplora puts in `scriptpubkey_type`. It has no bare multisig
+    /// type and reports those scripts as "unknown".
+    pub fn esplora_name(self) -> &'static str {
+        match self {
+            ScriptType::Multisig => "unknown",
+            other => other.name(),
+        }
+    }
+}
+
+/// `OP_m <pubkey>... OP_n OP_CHECKMULTISIG` with 1 <= m <= n <= 16.
+fn is_multisig(script: &[u8]) -> bool {
+    let Ok(ins) = instructions(script) else {
+        return false;
+    };
+    let [Instruction::Op(m @ 0x51..=0x60), keys @ .., Instruction::Op(n @ 0x51..=0x60), Instruction::Op(OP_CHECKMULTISIG)] =
+        ins.as_slice()
+    else {
+        return false;
+    };
+    let (m, n) = ((m - 0x50) as usize, (n - 0x50) as usize);
+    m <= n
+        && keys.len() == n
+        && keys.iter().all(
+            |key| matches!(key, Instruction::Push(data) if data.len() == 33 || data.len() == 65),
+        )
+}
+
+/// Compares the asm, type and address fields that accompany a scriptpubkey
+/// with what the hex actually encodes. Returns one message per mismatch.
+pub fn check_scriptpubkey_fields(
+    script_hex: &str,
+    asm: &str,
+    script_type_name: &str,
+    address: Option<&str>,
+    network: Network,
+) -> Vec<String> {
+    let script = match hex::decode(script_hex) {
+        Ok(script) => script,
+        Err(_) => return vec![format!("invalid scriptpubkey hex {}", script_hex)],
+    };
+
+    let mut mismatches = Vec::new();
+    let expected_asm = disassemble(&script);
+    if asm != expected_asm {
+        mismatches.push(format!("asm {:?} != {:?}", asm, expected_asm));
+    }
+    let expected_type = ScriptType::classify(&script).esplora_name();
+    if script_type_name != expected_type {
+        mismatches.push(format!("type {} != {}", script_type_name, expected_type));
+    }
+    let expected_address = script_to_address(&script, network);
+    if address != expected_address.as_deref() {
+        mismatches.push(format!("address {:?} != {:?}", address, expected_address));
+    }
+    mismatches
+}
+
+impl Transaction {
+    /// Lists every `*_asm`, `scriptpubkey_type` or `scriptpubkey_address`
+    /// field that disagrees with the script hex it describes.
+    pub fn script_field_mismatches(&self, network: Network) -> Vec<String> {
+        let mut mismatches = Vec::new();
+
+        for (i, input) in self.vin.iter().enumerate() {
+            let prevout = &input.prevout;
+            for m in check_scriptpubkey_fields(
+                &prevout.scriptpubkey,
+                &prevout.scriptpubkey_asm,
+                &prevout.scriptpubkey_type,
+                Some(prevout.scriptpubkey_address.as_str()).filter(|a| !a.is_empty()),
+                network,
+            ) {
+                mismatches.push(format!("vin[{}].prevout: {}", i, m));
+            }
+
+            match hex::decode(&input.scriptsig) {
+                Ok(scriptsig) => {
+                    let expected_asm = disassemble(&scriptsig);
+                    if input.scriptsig_asm != expected_asm {
+                        mismatches.push(format!(
+                            "vin[{}]: scriptsig asm {:?} != {:?}",
+                            i, input.scriptsig_asm, expected_asm
+                        ));
+                    }
+                }
+                Err(_) => mismatches.push(format!("vin[{}]: invalid scriptsig hex", i)),
+            }
+        }
+
+        for (i, output) in self.vout.iter().enumerate() {
+            for m in check_scriptpubkey_fields(
+                &output.scriptpubkey,
+                &output.scriptpubkey_asm,
+                &output.scriptpubkey_type,
+                output.scriptpubkey_address.as_deref(),
+                network,
+            ) {
+                mismatches.push(format!("vout[{}]: {}", i, m));
+            }
+        }
+        mismatches
+    }
+
+    /// Rewrites every asm, type and address field from the script hex.
+    pub fn recompute_script_fields(&mut self, network: Network) -> Result<(), String> {
+        for input in &mut self.vin {
+            input.prevout.recompute_script_fields(network)?;
+            let scriptsig = hex::decode(&input.scriptsig)
+                .map_err(|_| format!("Invalid scriptsig: {}", input.scriptsig))?;
+            input.scriptsig_asm = disassemble(&scriptsig);
+        }
+        for output in &mut self.vout {
+            output.recompute_script_fields(network)?;
+        }
+        Ok(())
+    }
+}
+
+impl Transaction {
+    /// Sigop cost counted against the block limit (`GetTransactionSigOpCost`):
+    /// legacy and P2SH sigops weigh 4, witness sigops 1.
+    pub fn sigop_cost(&self) -> Result<usize, String> {
+        let mut legacy = 0;
+        for input in &self.vin {
+            let scriptsig = hex::decode(&input.scriptsig)
+                .map_err(|_| format!("Invalid scriptsig: {}", input.scriptsig))?;
+            legacy += sigop_count(&scriptsig, false);
+        }
+        for output in &self.vout {
+            let script = hex::decode(&output.scriptpubkey)
+                .map_err(|_| format!("Invalid scriptpubkey: {}", output.scriptpubkey))?;
+            legacy += sigop_count(&script, false);
+        }
+        let mut cost = legacy * 4;
+        if self.is_coinbase() {
+            return Ok(cost);
+        }
+
+        for input in &self.vin {
+            let scriptsig = hex::decode(&input.scriptsig).unwrap();
+            let prevout = hex::decode(&input.prevout.scriptpubkey)
+                .map_err(|_| format!("Invalid scriptpubkey: {}", input.prevout.scriptpubkey))?;
+
+            // P2SH: the redeem script is the last push; a non push-only scriptsig counts nothing
+            let mut program_script = prevout.clone();
+            if ScriptType::classify(&prevout) == ScriptType::P2sh {
+                if let Some(redeem) = parse_pushes(&scriptsig)
+                    .ok()
+                    .and_then(|p| p.last().cloned())
+                {
+                    cost += sigop_count(&redeem, true) * 4;
+                    program_script = redeem;
+                }
+            }
+
+            // Witness v0: P2WPKH is one sigop, P2WSH counts its witness script
+            let witness = input.witness.as_deref().unwrap_or_default();
+            match witness_program(&program_script) {
+                Some((0, program)) if program.len() == 20 => cost += 1,
+                Some((0, program)) if program.len() == 32 => {
+                    if let Some(witness_script) = witness.last() {
+                        let witness_script = hex::decode(witness_script)
+                            .map_err(|_| format!("Invalid witness item: {}", witness_script))?;
+                        cost += sigop_count(&witness_script, true);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(cost)
+    }
+}
+
+impl PrevOut {
+    /// Derives `scriptpubkey_asm`, `scriptpubkey_type` and
+    /// `scriptpubkey_address` from `scriptpubkey` instead of trusting them.
+    pub fn recompute_script_fields(&mut self, network: Network) -> Result<(), String> {
+        let script = hex::decode(&self.scriptpubkey)
+            .map_err(|_| format!("Invalid scriptpubkey: {}", self.scriptpubkey))?;
+        self.scriptpubkey_asm = disassemble(&script);
+        self.scriptpubkey_type = ScriptType::classify(&script).esplora_name().to_string();
+        self.scriptpubkey_address = script_to_address(&script, network).unwrap_or_default();
+        Ok(())
+    }
+}
+
+impl Output {
+    /// Builds an output paying to `script`, with the descriptive fields filled in.
+    pub fn from_script(script: &[u8], value: u64, network: Network) -> Output {
+        Output {
+            scriptpubkey: hex::encode(script),
+            scriptpubkey_asm: disassemble(script),
+            scriptpubkey_type: ScriptType::classify(script).esplora_name().to_string(),
+            scriptpubkey_address: script_to_address(script, network),
+            value,
+        }
+    }
+
+    /// Derives the asm, type and address fields from `scriptpubkey`.
+    pub fn recompute_script_fields(&mut self, network: Network) -> Result<(), String> {
+        let script = hex::decode(&self.scriptpubkey)
+            .map_err(|_| format!("Invalid scriptpubkey: {}", self.scriptpubkey))?;
+        *self = Output::from_script(&script, self.value, network);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DER_SIG: &str =
+        "304502207fa7a6d1e0ee81132a269ad84e68d695483745cde8b541e3bf630749894e342a\
+                           022100c1f7ab20e13e22fb95281a870f3dcf38d782e53023ee313d741ad0b1fa1ab3ea";
+    const PUBKEY: &str = "03b0da749730dc9b4b1f4a14d6902877a92541f5368778853d9c4a0cb7802dcfb2";
+
+    fn asm(script_hex: &str, decode_sighash: bool) -> String {
+        core_asm(&hex::decode(script_hex).unwrap(), decode_sighash)
+    }
+
+    fn scriptsig(sighash_type: &str) -> String {
+        let sig = hex::decode(format!("{}{}", DER_SIG, sighash_type)).unwrap();
+        let mut script = push_data(&sig);
+        script.extend(push_data(&hex::decode(PUBKEY).unwrap()));
+        hex::encode(script)
+    }
+
+    #[test]
+    fn core_asm_decodes_defined_sighash_types() {
+        for (sighash_type, shown) in [
+            ("01", "[ALL]"),
+            ("02", "[NONE]"),
+            ("03", "[SINGLE]"),
+            ("81", "[ALL|ANYONECANPAY]"),
+            ("82", "[NONE|ANYONECANPAY]"),
+            ("83", "[SINGLE|ANYONECANPAY]"),
+            // Undefined types keep the byte
+            ("00", "00"),
+            ("80", "80"),
+            ("84", "84"),
+        ] {
+            assert_eq!(
+                asm(&scriptsig(sighash_type), true),
+                format!("{}{} {}", DER_SIG, shown, PUBKEY)
+            );
+            assert_eq!(
+                asm(&scriptsig(sighash_type), false),
+                format!("{}{} {}", DER_SIG, sighash_type, PUBKEY)
+            );
+        }
+        // Not strict DER: the S length is one short
+        let bad = scriptsig("01").replacen("022100c1", "022000c1", 1);
+        assert!(asm(&bad, true).ends_with(&format!("01 {}", PUBKEY)));
+        // Nor in an unspendable script
+        let unspendable = format!("6a{}", scriptsig("01"));
+        assert!(asm(&unspendable, true).starts_with(&format!("OP_RETURN {}01 ", DER_SIG)));
+    }
+
+    #[test]
+    fn core_asm_shows_small_pushes_as_numbers() {
+        assert_eq!(
+            asm(
+                "00514f60010102ff00018004ffffff7f04ffffffff0500000000ff",
+                false
+            ),
+            "0 1 -1 16 1 255 0 2147483647 -2147483647 00000000ff"
+        );
+        assert_eq!(
+            asm("76a914751e76e8199196d454941c45d1b3a323f1433bd688ac", false),
+            "OP_DUP OP_HASH160 751e76e8199196d454941c45d1b3a323f1433bd6 OP_EQUALVERIFY OP_CHECKSIG"
+        );
+        assert_eq!(
+            asm("b1b2babbff", false),
+            "OP_CHECKLOCKTIMEVERIFY OP_CHECKSEQUENCEVERIFY OP_CHECKSIGADD OP_UNKNOWN OP_INVALIDOPCODE"
+        );
+    }
+
+    #[test]
+    fn core_asm_marks_truncated_pushes() {
+        assert_eq!(asm("510201", false), "1 [error]");
+        assert_eq!(asm("4d01", false), "[error]");
+    }
+}
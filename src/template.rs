@@ -0,0 +1,88 @@
+use crate::block::{
+    calculate_witness_commitment, witness_commitment_script, WITNESS_RESERVED_VALUE,
+};
+use crate::coinbase::{coinbase_scriptsig, create_coinbase_transaction};
+use crate::config::Config;
+use crate::consensus::ConsensusParams;
+use crate::network::Network;
+use crate::tx::{Output, Transaction};
+
+/// Transactions for the next block, coinbase first, with the amounts the
+/// coinbase was allowed to claim.
+pub struct BlockTemplate {
+    pub height: u32,
+    pub subsidy: u64,
+    pub total_fees: u64,
+    pub transactions: Vec<Transaction>,
+}
+
+impl BlockTemplate {
+    /// Builds the coinbase for `height` in front of the selected
+    /// transactions. The coinbase claims the subsidy for that height plus the
+    /// fees of `selected`, and commits to their wtxids if any of them has a
+    /// witness, so the transaction list is final before any txid is computed.
+    pub fn new(
+        config: &Config,
+        params: &ConsensusParams,
+        height: u32,
+        selected: Vec<Transaction>,
+    ) -> Result<BlockTemplate, String> {
+        let subsidy = params.block_subsidy(height);
+        let total_fees = selected.iter().map(|tx| tx.fee()).sum();
+
+        let scriptsig = coinbase_scriptsig(height, 0, config.coinbase_tag.as_bytes())?;
+        let coinbase = create_coinbase_transaction(
+            subsidy,
+            total_fees,
+            &scriptsig,
+            &config.payouts,
+            config.operator_fee.as_ref(),
+            config.network,
+        )?;
+
+        let mut transactions = vec![coinbase];
+        transactions.extend(selected);
+        if transactions[1..].iter().any(|tx| tx.has_witness()) {
+            add_witness_commitment(&mut transactions, config.network)?;
+        }
+        check_coinbase_value(&transactions[0], subsidy, total_fees)?;
+
+        Ok(BlockTemplate {
+            height,
+            subsidy,
+            total_fees,
+            transactions,
+        })
+    }
+}
+
+/// Appends the BIP141 commitment output to the coinbase (`transactions[0]`)
+/// and gives it the witness reserved value as its only witness item.
+fn add_witness_commitment(
+    transactions: &mut [Transaction],
+    network: Network,
+) -> Result<(), String> {
+    let commitment = calculate_witness_commitment(transactions, &WITNESS_RESERVED_VALUE)?;
+    let script = witness_commitment_script(&commitment)?;
+
+    let coinbase = &mut transactions[0];
+    coinbase.vin[0].witness = Some(vec![hex::encode(WITNESS_RESERVED_VALUE)]);
+    coinbase.vout.push(Output::from_script(&script, 0, network));
+    Ok(())
+}
+
+/// Rejects a coinbase that pays out more than subsidy + fees (`bad-cb-amount`).
+pub fn check_coinbase_value(coinbase: &Transaction, subsidy: u64, fees: u64) -> Result<(), String> {
+    let paid = coinbase
+        .vout
+        .iter()
+        .try_fold(0u64, |acc, o| acc.checked_add(o.value))
+        .ok_or("Coinbase output value overflows")?;
+    if paid > subsidy + fees {
+        return Err(format!(
+            "Coinbase pays {} but subsidy + fees is only {} + {}",
+            paid, subsidy, fees
+        ));
+    }
+    Ok(())
+}
@@ -4,6 +4,8 @@ use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
 use crate::block::double_sha256;
+use crate::network::Network;
+use crate::script::disassemble;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
@@ -44,8 +46,83 @@ pub struct Output {
 }
 
 impl Transaction {
+    /// Parses one serialized transaction, with or without the BIP144 witness
+    /// section. Prevouts aren't part of the serialization, so they are left
+    /// empty; the output fields are derived from the scripts for `network`.
+    pub fn parse(reader: &mut ByteReader, network: Network) -> Result<Transaction, String> {
+        let version = reader.read_u32()? as i32;
+
+        // Marker and flag; a non-witness transaction can't have zero inputs
+        let segwit = reader.peek(2) == Some(&[0x00, 0x01][..]);
+        if segwit {
+            reader.read_bytes(2)?;
+        }
+
+        let input_count = reader.read_varint()?;
+        let mut vin = Vec::new();
+        for _ in 0..input_count {
+            let mut txid = reader.read_bytes(32)?.to_vec();
+            txid.reverse();
+            let txid = hex::encode(txid);
+            let vout = reader.read_u32()?;
+            let script_len = reader.read_varint()? as usize;
+            let scriptsig = reader.read_bytes(script_len)?;
+            let sequence = reader.read_u32()?;
+
+            let is_coinbase = vout == 0xffffffff && txid.bytes().all(|c| c == b'0');
+            vin.push(Input {
+                txid,
+                vout,
+                prevout: PrevOut {
+                    scriptpubkey: String::new(),
+                    scriptpubkey_asm: String::new(),
+                    scriptpubkey_type: if is_coinbase { "coinbase" } else { "" }.to_string(),
+                    scriptpubkey_address: String::new(),
+                    value: 0,
+                },
+                scriptsig: hex::encode(scriptsig),
+                scriptsig_asm: disassemble(scriptsig),
+                witness: None,
+                is_coinbase,
+                sequence,
+            });
+        }
+
+        let output_count = reader.read_varint()?;
+        let mut vout = Vec::new();
+        for _ in 0..output_count {
+            let value = reader.read_u64()?;
+            let script_len = reader.read_varint()? as usize;
+            let script = reader.read_bytes(script_len)?;
+            vout.push(Output::from_script(script, value, network));
+        }
+
+        if segwit {
+            for input in &mut vin {
+                let item_count = reader.read_varint()?;
+                let mut items = Vec::new();
+                for _ in 0..item_count {
+                    let len = reader.read_varint()? as usize;
+                    items.push(hex::encode(reader.read_bytes(len)?));
+                }
+                // Esplora leaves out empty witnesses
+                if !items.is_empty() {
+                    input.witness = Some(items);
+                }
+            }
+        }
+
+        let locktime = reader.read_u32()?;
+        Ok(Transaction {
+            version,
+            locktime,
+            vin,
+            vout,
+        })
+    }
+
     pub fn is_basic_valid(&self) -> bool {
-        if self.vin.len() == 0 || self.vout.len() == 0 {
+        if self.vin.is_empty() || self.vout.is_empty() {
             return false;
         }
 
@@ -145,6 +222,11 @@ impl Transaction {
             );
         }
 
+        // Without witness data the wtxid is the txid
+        if !self.has_witness() {
+            return self.calculate_txid();
+        }
+
         let mut data = Vec::new();
 
         // Transaction version
@@ -207,7 +289,7 @@ impl Transaction {
         data.extend_from_slice(&self.locktime.to_le_bytes());
 
         // Double SHA-256 to get the wtxid
-        let hash = Sha256::digest(&Sha256::digest(&data));
+        let hash = Sha256::digest(Sha256::digest(&data));
         Ok(hex::encode(hash.iter().rev().copied().collect::<Vec<u8>>()))
     }
 
@@ -233,7 +315,7 @@ impl Transaction {
     }
 
     // Calculate the base size of the transaction (size without witness data)
-    fn base_size(&self) -> usize {
+    pub fn base_size(&self) -> usize {
         let mut data = Vec::new();
 
         // Transaction version
@@ -267,7 +349,7 @@ impl Transaction {
     }
 
     // Calculate the total size of the transaction (size with witness data)
-    fn total_size(&self) -> usize {
+    pub fn total_size(&self) -> usize {
         let mut data = Vec::new();
 
         // Transaction version
@@ -322,12 +404,77 @@ impl Transaction {
         data.len()
     }
     // Check if the transaction has witness data
-    fn has_witness(&self) -> bool {
+    pub fn has_witness(&self) -> bool {
         self.vin.iter().any(|input| input.witness.is_some())
     }
 }
 
-fn serialize_varint(value: u64) -> Vec<u8> {
+/// Cursor over serialized transactions and blocks.
+pub struct ByteReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        ByteReader { data, pos: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    pub fn peek(&self, n: usize) -> Option<&'a [u8]> {
+        // n can come from an untrusted varint
+        let end = self.pos.checked_add(n)?;
+        self.data.get(self.pos..end)
+    }
+
+    pub fn read_bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let bytes = self.peek(n).ok_or_else(|| {
+            format!(
+                "Unexpected end of data: wanted {} bytes at offset {}, {} left",
+                n,
+                self.pos,
+                self.data.len() - self.pos
+            )
+        })?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, String> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, String> {
+        let bytes = self.read_bytes(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// CompactSize integer, rejecting non-canonical encodings like Core does.
+    pub fn read_varint(&mut self) -> Result<u64, String> {
+        let (value, min) = match self.read_bytes(1)?[0] {
+            0xfd => (
+                u16::from_le_bytes(self.read_bytes(2)?.try_into().unwrap()) as u64,
+                0xfd,
+            ),
+            0xfe => (self.read_u32()? as u64, 0x10000),
+            0xff => (self.read_u64()?, 0x100000000),
+            n => return Ok(n as u64),
+        };
+        if value < min {
+            return Err(format!(
+                "Non-canonical varint {} at offset {}",
+                value, self.pos
+            ));
+        }
+        Ok(value)
+    }
+}
+
+pub fn serialize_varint(value: u64) -> Vec<u8> {
     match value {
         0..=0xFC => vec![value as u8],
         0xFD..=0xFFFF => {
@@ -0,0 +1,767 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::block::Block;
+use crate::chain::{display_order, HeaderChain};
+use crate::consensus::COINBASE_MATURITY;
+use crate::network::Network;
+use crate::tx::{Output, PrevOut, Transaction};
+
+/// A reference to a transaction output.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    pub txid: String,
+    pub vout: u32,
+}
+
+/// An unspent output and the block that created it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Coin {
+    pub prevout: PrevOut,
+    /// Height of the creating block; 0 for coins the set started with.
+    pub height: u32,
+    /// Coinbase outputs can't be spent until they mature.
+    pub is_coinbase: bool,
+}
+
+impl Coin {
+    /// A coinbase output can only be spent in a block at least
+    /// `COINBASE_MATURITY` blocks above its own.
+    pub fn check_maturity(&self, outpoint: &OutPoint, spend_height: u32) -> Result<(), String> {
+        let depth = spend_height.saturating_sub(self.height);
+        if self.is_coinbase && depth < COINBASE_MATURITY {
+            return Err(premature_spend_error(outpoint, self.height, depth));
+        }
+        Ok(())
+    }
+}
+
+/// What connecting a block removed from the set, to put back on disconnect.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BlockUndo {
+    pub block_hash: String,
+    /// Coins the block spent, in the order it spent them.
+    pub spent: Vec<(OutPoint, Coin)>,
+}
+
+/// How `reorg_to` moved the set.
+pub struct Reorg {
+    pub disconnected: usize,
+    pub connected: usize,
+    /// Non-coinbase transactions of the disconnected blocks, oldest block
+    /// first, with their prevouts.
+    pub transactions: Vec<Transaction>,
+}
+
+/// Unspent outputs as of `best_block`, with undo data for every block
+/// connected so far. Lives in a directory holding the snapshot,
+/// `utxo.json`, and every stored block as `blocks/<hash>.dat`, which
+/// connecting a branch or disconnecting a block needs.
+pub struct UtxoSet {
+    dir: PathBuf,
+    /// Hash of the last connected block; empty before the first.
+    pub best_block: String,
+    coins: HashMap<OutPoint, Coin>,
+    undo: Vec<BlockUndo>,
+}
+
+/// On-disk form of a `UtxoSet`; JSON needs the coins as a list.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    best_block: String,
+    coins: Vec<(OutPoint, Coin)>,
+    undo: Vec<BlockUndo>,
+}
+
+impl UtxoSet {
+    /// Starts a set from the outputs `txs` spend that no transaction in the
+    /// mempool creates, i.e. the coins that existed before the chain, using
+    /// their prevouts. `mempool_txids` has every mempool transaction, so an
+    /// output of one that was rejected never becomes a coin.
+    pub fn from_mempool(
+        dir: &Path,
+        txs: &[Transaction],
+        mempool_txids: &HashSet<String>,
+    ) -> UtxoSet {
+        let coins = txs
+            .iter()
+            .flat_map(|tx| &tx.vin)
+            .filter(|input| !input.is_coinbase && !mempool_txids.contains(&input.txid))
+            .map(|input| {
+                let coin = Coin {
+                    prevout: input.prevout.clone(),
+                    height: 0,
+                    is_coinbase: false,
+                };
+                (outpoint(&input.txid, input.vout), coin)
+            })
+            .collect();
+        UtxoSet {
+            dir: dir.to_path_buf(),
+            best_block: String::new(),
+            coins,
+            undo: Vec::new(),
+        }
+    }
+
+    /// Reads the snapshot in `dir` written by `save`; None if there isn't
+    /// one yet.
+    pub fn load(dir: &Path) -> Result<Option<UtxoSet>, String> {
+        let path = dir.join(SNAPSHOT_FILE);
+        let data = match fs::read_to_string(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("Failed to read {}: {}", path.display(), e)),
+        };
+        let snapshot: Snapshot = serde_json::from_str(&data)
+            .map_err(|e| format!("Invalid UTXO snapshot {}: {}", path.display(), e))?;
+        Ok(Some(UtxoSet {
+            dir: dir.to_path_buf(),
+            best_block: snapshot.best_block,
+            coins: snapshot.coins.into_iter().collect(),
+            undo: snapshot.undo,
+        }))
+    }
+
+    /// Writes the set to a temporary file and renames it over `path`, so a
+    /// crash leaves the previous snapshot intact.
+    pub fn save(&self) -> Result<(), String> {
+        let snapshot = Snapshot {
+            best_block: self.best_block.clone(),
+            coins: self
+                .coins
+                .iter()
+                .map(|(outpoint, coin)| (outpoint.clone(), coin.clone()))
+                .collect(),
+            undo: self.undo.clone(),
+        };
+        let data = serde_json::to_string(&snapshot)
+            .map_err(|e| format!("Failed to encode the UTXO snapshot: {}", e))?;
+        let path = self.dir.join(SNAPSHOT_FILE);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data).map_err(|e| format!("Failed to write {}: {}", tmp.display(), e))?;
+        fs::rename(&tmp, &path).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    pub fn coin_count(&self) -> usize {
+        self.coins.len()
+    }
+
+    /// Keeps the block so a reorg can connect or disconnect it later.
+    pub fn store_block(&self, block: &Block) -> Result<(), String> {
+        let blocks = self.dir.join(BLOCKS_DIR);
+        fs::create_dir_all(&blocks)
+            .map_err(|e| format!("Failed to create {}: {}", blocks.display(), e))?;
+        block.write_raw(&blocks.join(format!("{}.dat", block.header.hash())))
+    }
+
+    fn read_block(&self, hash: &str, network: Network) -> Result<Block, String> {
+        let path = self.dir.join(BLOCKS_DIR).join(format!("{}.dat", hash));
+        let data =
+            fs::read(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Block::parse(&data, network)
+    }
+
+    /// Spends the block's inputs and adds its outputs as coins at `height`.
+    /// Every input must spend a coin in the set or an
+    /// earlier output of the same block; otherwise the set is left unchanged.
+    pub fn connect_block(&mut self, block: &Block, height: u32) -> Result<(), String> {
+        let hash = block.header.hash();
+        let parent = display_order(&block.header.previous_block_hash);
+        if !self.best_block.is_empty() && parent != self.best_block {
+            return Err(format!(
+                "Block {} builds on {}, not on the UTXO tip {}",
+                hash, parent, self.best_block
+            ));
+        }
+
+        // Check every spend before changing anything
+        let mut created = HashSet::new();
+        let mut spent = HashSet::new();
+        for (tx, txid) in block.transactions.iter().zip(&block.txids) {
+            for input in tx.vin.iter().filter(|input| !input.is_coinbase) {
+                let spend = outpoint(&input.txid, input.vout);
+                if let Some(coin) = self.coins.get(&spend) {
+                    coin.check_maturity(&spend, height)
+                        .map_err(|e| format!("{}: {}", txid, e))?;
+                } else if spend.txid == block.txids[0] {
+                    // This block's own coinbase
+                    return Err(format!(
+                        "{}: {}",
+                        txid,
+                        premature_spend_error(&spend, height, 0)
+                    ));
+                }
+                let exists = self.coins.contains_key(&spend) || created.contains(&spend);
+                if !exists || !spent.insert(spend) {
+                    return Err(format!(
+                        "{} spends {}:{}, which is missing or already spent",
+                        txid, input.txid, input.vout
+                    ));
+                }
+            }
+            let spendable = tx
+                .vout
+                .iter()
+                .enumerate()
+                .filter(|(_, o)| !is_unspendable(o));
+            created.extend(spendable.map(|(vout, _)| outpoint(txid, vout as u32)));
+        }
+
+        let mut undo = BlockUndo {
+            block_hash: hash.clone(),
+            spent: Vec::new(),
+        };
+        for (tx, txid) in block.transactions.iter().zip(&block.txids) {
+            for input in tx.vin.iter().filter(|input| !input.is_coinbase) {
+                let spend = outpoint(&input.txid, input.vout);
+                let coin = self.coins.remove(&spend).unwrap();
+                undo.spent.push((spend, coin));
+            }
+            for (vout, output) in tx.vout.iter().enumerate() {
+                if is_unspendable(output) {
+                    continue;
+                }
+                let coin = Coin {
+                    prevout: to_prevout(output),
+                    height,
+                    is_coinbase: tx.is_coinbase(),
+                };
+                self.coins.insert(outpoint(txid, vout as u32), coin);
+            }
+        }
+        self.undo.push(undo);
+        self.best_block = hash;
+        Ok(())
+    }
+
+    /// Reverses `connect_block` for the block at the UTXO tip: removes its
+    /// outputs and restores the coins it spent. Returns the block and its
+    /// non-coinbase transactions with their prevouts filled in again.
+    pub fn disconnect_tip(
+        &mut self,
+        network: Network,
+    ) -> Result<(Block, Vec<Transaction>), String> {
+        let undo = self
+            .undo
+            .pop()
+            .ok_or("No block to disconnect from the UTXO set")?;
+        let block = self.read_block(&undo.block_hash, network)?;
+
+        for (tx, txid) in block.transactions.iter().zip(&block.txids) {
+            for vout in 0..tx.vout.len() as u32 {
+                self.coins.remove(&outpoint(txid, vout));
+            }
+        }
+        // Outputs the block both created and spent don't come back
+        let mut restored = HashMap::new();
+        for (spend, coin) in undo.spent {
+            restored.insert(spend.clone(), coin.prevout.clone());
+            if !block.txids.contains(&spend.txid) {
+                self.coins.insert(spend, coin);
+            }
+        }
+        self.best_block = match self.undo.last() {
+            Some(_) => display_order(&block.header.previous_block_hash),
+            None => String::new(),
+        };
+
+        let mut transactions = block.transactions[1..].to_vec();
+        for input in transactions.iter_mut().flat_map(|tx| &mut tx.vin) {
+            if let Some(prevout) = restored.remove(&outpoint(&input.txid, input.vout)) {
+                input.prevout = prevout;
+            }
+        }
+        Ok((block, transactions))
+    }
+
+    /// Moves the set to block `target` of `chain`: disconnects blocks back
+    /// to the fork point with `target`'s branch, then connects that branch
+    /// from the stored blocks. An empty `target` disconnects every block.
+    pub fn reorg_to(
+        &mut self,
+        chain: &HeaderChain,
+        target: &str,
+        network: Network,
+    ) -> Result<Reorg, String> {
+        let branch = match target {
+            "" => Vec::new(),
+            target => chain.path_to(target),
+        };
+        let mut disconnected = Vec::new();
+        while !self.best_block.is_empty() && !branch.contains(&self.best_block) {
+            let (_, transactions) = self.disconnect_tip(network)?;
+            disconnected.push(transactions);
+        }
+
+        let fork = branch.iter().position(|hash| *hash == self.best_block);
+        let to_connect = &branch[fork.map_or(0, |i| i + 1)..];
+        for hash in to_connect {
+            let block = self.read_block(hash, network)?;
+            self.connect_block(&block, chain.get(hash).unwrap().height)?;
+        }
+        Ok(Reorg {
+            disconnected: disconnected.len(),
+            connected: to_connect.len(),
+            transactions: disconnected.into_iter().rev().flatten().collect(),
+        })
+    }
+
+    /// Keeps the mempool transactions whose inputs all spend a coin in the
+    /// set, with a matching prevout, or an output of another kept mempool
+    /// transaction. Dropping a transaction also drops its descendants.
+    pub fn filter_mempool(&self, txs: Vec<Transaction>) -> Vec<Transaction> {
+        let mut txs = txs
+            .into_iter()
+            .filter_map(|tx| Some((tx.calculate_txid().ok()?, tx)))
+            .collect::<Vec<_>>();
+        loop {
+            let outputs = txs
+                .iter()
+                .map(|(txid, tx)| (txid.clone(), tx.vout.len() as u32))
+                .collect::<HashMap<_, _>>();
+            let before = txs.len();
+            txs.retain(|(_, tx)| {
+                tx.vin.iter().all(|input| {
+                    match self.coins.get(&outpoint(&input.txid, input.vout)) {
+                        Some(coin) => {
+                            coin.prevout.scriptpubkey == input.prevout.scriptpubkey
+                                && coin.prevout.value == input.prevout.value
+                        }
+                        None => outputs
+                            .get(&input.txid)
+                            .is_some_and(|&count| input.vout < count),
+                    }
+                })
+            });
+            if txs.len() == before {
+                return txs.into_iter().map(|(_, tx)| tx).collect();
+            }
+        }
+    }
+
+    /// Splits filtered mempool transactions into those a block at `height`
+    /// may include and those that spend a coinbase output that is immature
+    /// at that height, along with their descendants. The held back ones stay
+    /// valid for a later block; the errors say why each was held back.
+    pub fn split_immature(
+        &self,
+        txs: Vec<Transaction>,
+        height: u32,
+    ) -> (Vec<Transaction>, Vec<Transaction>, Vec<String>) {
+        let mut held = HashSet::new();
+        let mut errors = Vec::new();
+        let txids = txs
+            .iter()
+            .map(|tx| tx.calculate_txid().unwrap_or_default())
+            .collect::<Vec<_>>();
+        loop {
+            let before = held.len();
+            for (tx, txid) in txs.iter().zip(&txids) {
+                if held.contains(txid) {
+                    continue;
+                }
+                for input in &tx.vin {
+                    let spend = outpoint(&input.txid, input.vout);
+                    let error = match self.coins.get(&spend) {
+                        Some(coin) => coin.check_maturity(&spend, height).err(),
+                        None if held.contains(&input.txid) => {
+                            Some(format!("spends held back {}:{}", input.txid, input.vout))
+                        }
+                        None => None,
+                    };
+                    if let Some(error) = error {
+                        errors.push(format!("{}: {}", txid, error));
+                        held.insert(txid.clone());
+                        break;
+                    }
+                }
+            }
+            if held.len() == before {
+                break;
+            }
+        }
+
+        let (immature, mature) = txs
+            .into_iter()
+            .zip(&txids)
+            .partition::<Vec<_>, _>(|(_, txid)| held.contains(*txid));
+        (
+            mature.into_iter().map(|(tx, _)| tx).collect(),
+            immature.into_iter().map(|(tx, _)| tx).collect(),
+            errors,
+        )
+    }
+}
+
+const SNAPSHOT_FILE: &str = "utxo.json";
+const BLOCKS_DIR: &str = "blocks";
+
+/// Bitcoin Core's reject reason, with the details.
+fn premature_spend_error(outpoint: &OutPoint, coin_height: u32, depth: u32) -> String {
+    format!(
+        "bad-txns-premature-spend-of-coinbase: {}:{} from height {} has {} of {} confirmations",
+        outpoint.txid, outpoint.vout, coin_height, depth, COINBASE_MATURITY
+    )
+}
+
+fn outpoint(txid: &str, vout: u32) -> OutPoint {
+    OutPoint {
+        txid: txid.to_string(),
+        vout,
+    }
+}
+
+/// OP_RETURN outputs can never be spent, so they never become coins.
+fn is_unspendable(output: &Output) -> bool {
+    output.scriptpubkey.starts_with("6a")
+}
+
+fn to_prevout(output: &Output) -> PrevOut {
+    PrevOut {
+        scriptpubkey: output.scriptpubkey.clone(),
+        scriptpubkey_asm: output.scriptpubkey_asm.clone(),
+        scriptpubkey_type: output.scriptpubkey_type.clone(),
+        scriptpubkey_address: output.scriptpubkey_address.clone().unwrap_or_default(),
+        value: output.value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::block::BlockHeader;
+    use crate::consensus::ConsensusParams;
+    use crate::merkle::{MerkleProof, MerkleTree};
+    use crate::pow::{bits_to_target, hash_meets_target};
+    use crate::tx::Input;
+
+    const NETWORK: Network = Network::Regtest;
+    const REGTEST_BITS: u32 = 0x207fffff;
+    const P2PKH: &str = "76a914111111111111111111111111111111111111111188ac";
+
+    /// An empty directory of its own for every test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("utxo-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn empty_set(dir: &Path) -> UtxoSet {
+        UtxoSet {
+            dir: dir.to_path_buf(),
+            best_block: String::new(),
+            coins: HashMap::new(),
+            undo: Vec::new(),
+        }
+    }
+
+    fn prevout(value: u64) -> PrevOut {
+        PrevOut {
+            scriptpubkey: P2PKH.to_string(),
+            scriptpubkey_asm: String::new(),
+            scriptpubkey_type: String::new(),
+            scriptpubkey_address: String::new(),
+            value,
+        }
+    }
+
+    fn coin(height: u32, is_coinbase: bool) -> Coin {
+        Coin {
+            prevout: prevout(50_000),
+            height,
+            is_coinbase,
+        }
+    }
+
+    fn input(txid: &str, vout: u32, is_coinbase: bool) -> Input {
+        Input {
+            txid: txid.to_string(),
+            vout,
+            prevout: prevout(50_000),
+            scriptsig: if is_coinbase { "0401020304" } else { "" }.to_string(),
+            scriptsig_asm: String::new(),
+            witness: None,
+            is_coinbase,
+            sequence: 0xffffffff,
+        }
+    }
+
+    /// A transaction spending `spends` into two outputs; `locktime` tells
+    /// otherwise identical transactions apart.
+    fn tx(spends: &[(&str, u32)], locktime: u32) -> Transaction {
+        let output = Output {
+            scriptpubkey: P2PKH.to_string(),
+            scriptpubkey_asm: String::new(),
+            scriptpubkey_type: String::new(),
+            scriptpubkey_address: None,
+            value: 20_000,
+        };
+        Transaction {
+            version: 2,
+            locktime,
+            vin: spends
+                .iter()
+                .map(|&(txid, vout)| input(txid, vout, false))
+                .collect(),
+            vout: vec![output.clone(), output],
+        }
+    }
+
+    fn coinbase(tag: u32) -> Transaction {
+        let mut tx = tx(&[], tag);
+        tx.vin.push(input(&"0".repeat(64), 0xffffffff, true));
+        tx
+    }
+
+    fn txid(tx: &Transaction) -> String {
+        tx.calculate_txid().unwrap()
+    }
+
+    /// A block on `parent` (display order, empty for a root) with a valid
+    /// regtest proof of work, read back the way `read_block` would.
+    fn block(parent: &str, time: u32, transactions: Vec<Transaction>) -> Block {
+        let txids = transactions.iter().map(txid).collect::<Vec<_>>();
+        let mut block = Block {
+            header: BlockHeader {
+                version: 0x20000000,
+                previous_block_hash: match parent {
+                    "" => "0".repeat(64),
+                    parent => display_order(parent),
+                },
+                merkle_root: MerkleTree::from_ids(&txids).unwrap().root_hex().unwrap(),
+                time,
+                bits: REGTEST_BITS,
+                nonce: 0,
+            },
+            transactions,
+            txids,
+            coinbase_branch: MerkleProof::default(),
+        };
+        let target = bits_to_target(REGTEST_BITS).unwrap();
+        while !hash_meets_target(&block.header.hash(), &target) {
+            block.header.nonce += 1;
+        }
+        Block::parse(&block.serialize(), NETWORK).unwrap()
+    }
+
+    /// Stores `block`, adds its header to `chain` and returns its hash.
+    fn add(chain: &mut HeaderChain, utxo: &UtxoSet, block: &Block) -> String {
+        let params = ConsensusParams::for_network(NETWORK);
+        utxo.store_block(block).unwrap();
+        chain.add(block.header.clone(), 0, &params).unwrap();
+        block.header.hash()
+    }
+
+    /// The coins as sorted JSON, to compare whole sets.
+    fn coins(utxo: &UtxoSet) -> Vec<String> {
+        let mut coins = utxo
+            .coins
+            .iter()
+            .map(|entry| serde_json::to_string(&entry).unwrap())
+            .collect::<Vec<_>>();
+        coins.sort();
+        coins
+    }
+
+    #[test]
+    fn connect_and_disconnect_restore_the_set() {
+        let dir = temp_dir("disconnect");
+        let mut utxo = empty_set(&dir);
+        let seed = "aa".repeat(32);
+        utxo.coins.insert(outpoint(&seed, 0), coin(0, false));
+        utxo.coins.insert(outpoint(&seed, 1), coin(0, false));
+        let before = coins(&utxo);
+
+        // The second transaction spends an output the block itself creates
+        let parent = tx(&[(&seed, 0)], 0);
+        let child = tx(&[(&txid(&parent), 0)], 0);
+        let other = tx(&[(&seed, 1)], 0);
+        let block = block(
+            "",
+            1_600_000_000,
+            vec![coinbase(1), parent.clone(), child.clone(), other],
+        );
+        utxo.store_block(&block).unwrap();
+        utxo.connect_block(&block, 1).unwrap();
+        assert_eq!(utxo.best_block, block.header.hash());
+        assert!(!utxo.coins.contains_key(&outpoint(&seed, 0)));
+        assert!(!utxo.coins.contains_key(&outpoint(&txid(&parent), 0)));
+        assert!(utxo.coins.contains_key(&outpoint(&txid(&parent), 1)));
+        assert!(utxo.coins[&outpoint(&block.txids[0], 0)].is_coinbase);
+        // Failing to connect leaves the set as it was
+        assert!(utxo.connect_block(&block, 2).is_err());
+
+        let (_, returned) = utxo.disconnect_tip(NETWORK).unwrap();
+        assert_eq!(utxo.best_block, "");
+        assert_eq!(coins(&utxo), before);
+        assert_eq!(returned.len(), 3);
+        assert_eq!(txid(&returned[1]), txid(&child));
+        // Prevouts come back from the undo data, including the output the
+        // block created itself
+        assert_eq!(returned[0].vin[0].prevout.value, 50_000);
+        assert_eq!(returned[1].vin[0].prevout.value, 20_000);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reorg_across_a_fork() {
+        let dir = temp_dir("reorg");
+        let params = ConsensusParams::for_network(NETWORK);
+        let mut chain = HeaderChain::open(&dir.join("chain.txt"), &params).unwrap();
+        let mut utxo = empty_set(&dir);
+        let seed = "bb".repeat(32);
+        for vout in 0..3 {
+            utxo.coins.insert(outpoint(&seed, vout), coin(0, false));
+        }
+
+        let time = 1_600_000_000;
+        let root = block("", time, vec![coinbase(0)]);
+        let root_hash = add(&mut chain, &utxo, &root);
+        utxo.connect_block(&root, 0).unwrap();
+        let at_fork = coins(&utxo);
+
+        // Branch B spends seed 0 and 1
+        let b1 = block(
+            &root_hash,
+            time + 1,
+            vec![coinbase(1), tx(&[(&seed, 0)], 0)],
+        );
+        let b1_hash = add(&mut chain, &utxo, &b1);
+        let b2 = block(&b1_hash, time + 2, vec![coinbase(2), tx(&[(&seed, 1)], 0)]);
+        let b2_hash = add(&mut chain, &utxo, &b2);
+        utxo.reorg_to(&chain, &b2_hash, NETWORK).unwrap();
+        assert_eq!(utxo.best_block, b2_hash);
+
+        // Branch C spends seed 0 differently and seed 2, and gets longer
+        let c1 = block(
+            &root_hash,
+            time + 3,
+            vec![coinbase(11), tx(&[(&seed, 0)], 1)],
+        );
+        let c1_hash = add(&mut chain, &utxo, &c1);
+        let c2 = block(&c1_hash, time + 4, vec![coinbase(12), tx(&[(&seed, 2)], 0)]);
+        let c2_hash = add(&mut chain, &utxo, &c2);
+        let c3 = block(&c2_hash, time + 5, vec![coinbase(13)]);
+        let c3_hash = add(&mut chain, &utxo, &c3);
+        assert_eq!(chain.tip().unwrap().header.hash(), c3_hash);
+
+        let reorg = utxo.reorg_to(&chain, &c3_hash, NETWORK).unwrap();
+        assert_eq!((reorg.disconnected, reorg.connected), (2, 3));
+        // Oldest disconnected block first
+        let returned = reorg.transactions.iter().map(txid).collect::<Vec<_>>();
+        assert_eq!(returned, vec![b1.txids[1].clone(), b2.txids[1].clone()]);
+        assert_eq!(utxo.best_block, c3_hash);
+        assert!(utxo.coins.contains_key(&outpoint(&seed, 1)));
+        assert!(!utxo.coins.contains_key(&outpoint(&b1.txids[1], 0)));
+        assert!(utxo.coins.contains_key(&outpoint(&c1.txids[1], 0)));
+        assert_eq!(utxo.coins[&outpoint(&c3.txids[0], 0)].height, 3);
+
+        // The seed's B spend is gone with its branch; the second one fits
+        let kept = utxo.filter_mempool(reorg.transactions);
+        assert_eq!(
+            kept.iter().map(txid).collect::<Vec<_>>(),
+            vec![b2.txids[1].clone()]
+        );
+
+        // Back to the fork point: exactly the coins it had
+        let reorg = utxo.reorg_to(&chain, &root_hash, NETWORK).unwrap();
+        assert_eq!((reorg.disconnected, reorg.connected), (3, 0));
+        assert_eq!(utxo.best_block, root_hash);
+        assert_eq!(coins(&utxo), at_fork);
+
+        // And over to B again, though it isn't the most-work branch
+        utxo.reorg_to(&chain, &b2_hash, NETWORK).unwrap();
+        assert_eq!(utxo.best_block, b2_hash);
+        assert!(!utxo.coins.contains_key(&outpoint(&seed, 1)));
+        assert!(utxo.coins.contains_key(&outpoint(&seed, 2)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn coinbase_spends_need_100_confirmations() {
+        let dir = temp_dir("maturity");
+        let mut utxo = empty_set(&dir);
+        let reward = "cc".repeat(32);
+        utxo.coins.insert(outpoint(&reward, 0), coin(10, true));
+        let block = block("", 1_600_000_000, vec![coinbase(1), tx(&[(&reward, 0)], 0)]);
+
+        let error = utxo.connect_block(&block, 109).unwrap_err();
+        assert!(
+            error.contains("bad-txns-premature-spend-of-coinbase"),
+            "{}",
+            error
+        );
+        assert!(error.contains("has 99 of 100 confirmations"), "{}", error);
+        assert!(utxo.coins.contains_key(&outpoint(&reward, 0)));
+
+        utxo.connect_block(&block, 110).unwrap();
+        assert!(!utxo.coins.contains_key(&outpoint(&reward, 0)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_block_cannot_spend_its_own_coinbase() {
+        let dir = temp_dir("own-coinbase");
+        let mut utxo = empty_set(&dir);
+        let reward = coinbase(1);
+        let spend = tx(&[(&txid(&reward), 0)], 0);
+        let block = block("", 1_600_000_000, vec![reward, spend]);
+
+        let error = utxo.connect_block(&block, 200).unwrap_err();
+        assert!(error.contains("has 0 of 100 confirmations"), "{}", error);
+        assert!(utxo.coins.is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn immature_spends_and_their_descendants_are_held_back() {
+        let dir = temp_dir("split");
+        let mut utxo = empty_set(&dir);
+        let (young, old, plain) = ("dd".repeat(32), "ee".repeat(32), "ff".repeat(32));
+        utxo.coins.insert(outpoint(&young, 0), coin(51, true));
+        utxo.coins.insert(outpoint(&old, 0), coin(50, true));
+        utxo.coins.insert(outpoint(&plain, 0), coin(150, false));
+
+        let immature = tx(&[(&young, 0)], 0);
+        let child = tx(&[(&txid(&immature), 1)], 0);
+        let mature = tx(&[(&old, 0)], 0);
+        let unrelated = tx(&[(&plain, 0)], 0);
+        // The child comes first, so holding it back takes a second pass
+        let txs = vec![
+            child.clone(),
+            mature.clone(),
+            immature.clone(),
+            unrelated.clone(),
+        ];
+
+        let (kept, held, errors) = utxo.split_immature(txs, 150);
+        assert_eq!(
+            kept.iter().map(txid).collect::<Vec<_>>(),
+            vec![txid(&mature), txid(&unrelated)]
+        );
+        assert_eq!(
+            held.iter().map(txid).collect::<Vec<_>>(),
+            vec![txid(&child), txid(&immature)]
+        );
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with(&txid(&immature)));
+        assert!(errors[0].contains("has 99 of 100 confirmations"));
+        assert!(errors[1].starts_with(&txid(&child)));
+        assert!(errors[1].contains("spends held back"));
+
+        // A block later the whole chain of spends can go in
+        let (kept, held, errors) = utxo.split_immature(kept.into_iter().chain(held).collect(), 151);
+        assert_eq!(kept.len(), 4);
+        assert!(held.is_empty() && errors.is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,71 +1,348 @@
-// use crate::tx::Transaction;
-// use secp256k1::{Message, PublicKey, Secp256k1, Signature};
-// use sha2::{Digest, Sha256};
-
-// pub fn validate_transaction(tx: &Transaction) -> Result<(), String> {
-//     let secp = Secp256k1::verification_only();
-
-//     for (i, input) in tx.vin.iter().enumerate() {
-//         if input.is_coinbase {
-//             continue;
-//         }
-
-//         // Validate the script type and extract the public key
-//         let prev_output = &input.prevout;
-//         let public_key = match prev_output.scriptpubkey_type.as_str() {
-//             "v1_p2tr" => {
-//                 let pubkey_hex = &prev_output.scriptpubkey_asm[29..];
-//                 PublicKey::from_slice(&hex::decode(pubkey_hex).map_err(|_| "Invalid public key")?)
-//                     .map_err(|_| "Invalid public key")?
-//             }
-//             _ => return Err("Unsupported script type".to_string()),
-//         };
-
-//         // Verify the signature
-//         let signature = Signature::from_der(&hex::decode(&input.witness.as_ref().unwrap()[0]).map_err(|_| "Invalid signature")?)
-//             .map_err(|_| "Invalid signature")?;
-
-//         // Assuming the sighash type is `SIGHASH_ALL`
-//         let sighash_all: u32 = 1;
-
-//         let mut sig_hash = Sha256::new();
-//         sig_hash.update(&tx.version.to_le_bytes());
-
-//         for (j, input) in tx.vin.iter().enumerate() {
-//             if i == j {
-//                 sig_hash.update(&hex::decode(&input.prevout.scriptpubkey).map_err(|_| "Invalid scriptpubkey")?);
-//             } else {
-//                 sig_hash.update(&[0; 32]);
-//                 sig_hash.update(&[0; 4]);
-//             }
-//             sig_hash.update(&input.sequence.to_le_bytes());
-//         }
-
-//         for output in &tx.vout {
-//             sig_hash.update(&output.value.to_le_bytes());
-//             sig_hash.update(&hex::decode(&output.scriptpubkey).map_err(|_| "Invalid scriptpubkey")?);
-//         }
-
-//         sig_hash.update(&tx.locktime.to_le_bytes());
-//         sig_hash.update(&sighash_all.to_le_bytes());
-
-//         let message = Message::from_slice(&sig_hash.finalize()).map_err(|_| "Invalid message")?;
-//         secp.verify(&message, &signature, &public_key)
-//             .map_err(|_| "Signature verification failed")?;
-//     }
-
-//     let total_output_value: u64 = tx.vout.iter().map(|output| output.value).sum();
-
-//     let mut total_input_value = 0;
-//     for input in &tx.vin {
-//         if !input.is_coinbase {
-//             total_input_value += input.prevout.value;
-//         }
-//     }
-
-//     if total_output_value > total_input_value {
-//         return Err("Total output value exceeds total input value".to_string());
-//     }
-
-//     Ok(())
-// }
+use std::fmt;
+
+use ripemd::Ripemd160;
+use secp256k1::{ecdsa, schnorr, Message, PublicKey, Secp256k1, VerifyOnly, XOnlyPublicKey};
+use sha2::{Digest, Sha256};
+
+use crate::cache::{ScriptCache, SigCache};
+use crate::parallel::parallel_map;
+use crate::script::{parse_pushes, ScriptType};
+use crate::tx::Transaction;
+
+/// Evaluate P2SH redeem scripts (BIP16).
+pub const VERIFY_P2SH: u32 = 1 << 0;
+/// Evaluate segwit v0 witness programs (BIP141/143).
+pub const VERIFY_WITNESS: u32 = 1 << 1;
+/// Evaluate segwit v1 taproot outputs (BIP341).
+pub const VERIFY_TAPROOT: u32 = 1 << 2;
+
+pub const VERIFY_ALL: u32 = VERIFY_P2SH | VERIFY_WITNESS | VERIFY_TAPROOT;
+
+/// Why a transaction can't go in a block.
+#[derive(Debug)]
+pub enum TxError {
+    /// It breaks a rule we check.
+    Invalid(String),
+    /// It spends a script we can't evaluate (P2WSH, other P2SH redeem
+    /// scripts, taproot script paths, bare scripts). It may well be valid,
+    /// but since that can't be shown here it is left out of blocks too.
+    Unsupported(String),
+}
+
+impl TxError {
+    fn context(self, prefix: &str) -> TxError {
+        match self {
+            TxError::Invalid(e) => TxError::Invalid(format!("{}: {}", prefix, e)),
+            TxError::Unsupported(e) => TxError::Unsupported(format!("{}: {}", prefix, e)),
+        }
+    }
+}
+
+impl From<String> for TxError {
+    fn from(e: String) -> TxError {
+        TxError::Invalid(e)
+    }
+}
+
+impl fmt::Display for TxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TxError::Invalid(e) | TxError::Unsupported(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Shared signature checker handed to the per-script-type verifiers.
+///
+/// Holds the signature and script caches, so keeping one verifier around
+/// makes revalidating the same mempool cheap.
+pub struct SigVerifier {
+    secp: Secp256k1<VerifyOnly>,
+    pub sig_cache: SigCache,
+    pub script_cache: ScriptCache,
+}
+
+impl SigVerifier {
+    pub fn new(sig_cache_size: usize, script_cache_size: usize) -> Self {
+        SigVerifier {
+            secp: Secp256k1::verification_only(),
+            sig_cache: SigCache::new(sig_cache_size),
+            script_cache: ScriptCache::new(script_cache_size),
+        }
+    }
+
+    /// Verifies a DER encoded ECDSA signature (without the sighash byte).
+    pub fn verify_ecdsa(
+        &self,
+        sighash: &[u8; 32],
+        pubkey: &[u8],
+        sig: &[u8],
+    ) -> Result<(), String> {
+        if self.sig_cache.contains(sighash, pubkey, sig) {
+            return Ok(());
+        }
+        let key = PublicKey::from_slice(pubkey).map_err(|_| "Invalid public key")?;
+        // Strict DER is a consensus rule since BIP66
+        let mut signature = ecdsa::Signature::from_der(sig).map_err(|_| "Invalid DER signature")?;
+        // High-S is non-standard but still valid by consensus
+        signature.normalize_s();
+        self.secp
+            .verify_ecdsa(&Message::from_digest(*sighash), &signature, &key)
+            .map_err(|_| "ECDSA signature verification failed".to_string())?;
+        self.sig_cache.insert(sighash, pubkey, sig);
+        Ok(())
+    }
+
+    /// Verifies a 64 byte BIP340 signature against an x-only key.
+    pub fn verify_schnorr(
+        &self,
+        sighash: &[u8; 32],
+        pubkey: &[u8],
+        sig: &[u8],
+    ) -> Result<(), String> {
+        if self.sig_cache.contains(sighash, pubkey, sig) {
+            return Ok(());
+        }
+        let key = XOnlyPublicKey::from_slice(pubkey).map_err(|_| "Invalid x-only public key")?;
+        let signature =
+            schnorr::Signature::from_slice(sig).map_err(|_| "Invalid schnorr signature")?;
+        self.secp
+            .verify_schnorr(&signature, &Message::from_digest(*sighash), &key)
+            .map_err(|_| "Schnorr signature verification failed".to_string())?;
+        self.sig_cache.insert(sighash, pubkey, sig);
+        Ok(())
+    }
+}
+
+/// Verifies a single input, dispatching on the prevout's script type.
+///
+/// Rules whose flag is not set are skipped the way a node that predates the
+/// soft fork would skip them, e.g. witness programs are anyone-can-spend
+/// without `VERIFY_WITNESS`.
+pub fn verify_input(
+    tx: &Transaction,
+    index: usize,
+    flags: u32,
+    verifier: &SigVerifier,
+) -> Result<(), TxError> {
+    let input = &tx.vin[index];
+    let scriptpubkey = hex::decode(&input.prevout.scriptpubkey)
+        .map_err(|_| format!("Invalid scriptpubkey: {}", input.prevout.scriptpubkey))?;
+
+    // Dispatch on the script itself, not the JSON's scriptpubkey_type
+    match ScriptType::classify(&scriptpubkey) {
+        ScriptType::P2pkh => Ok(tx.verify_p2pkh_input(index, verifier)?),
+        ScriptType::V0P2wpkh => {
+            if flags & VERIFY_WITNESS == 0 {
+                return Ok(());
+            }
+            if !input.scriptsig.is_empty() {
+                return Err("Native segwit input with non-empty scriptsig"
+                    .to_string()
+                    .into());
+            }
+            Ok(tx.verify_p2wpkh_input(index, &scriptpubkey[2..], verifier)?)
+        }
+        ScriptType::P2sh => {
+            let redeem_script = p2sh_redeem_script(tx, index, &scriptpubkey)?;
+            if flags & VERIFY_P2SH == 0 {
+                return Ok(());
+            }
+            if ScriptType::classify(&redeem_script) == ScriptType::V0P2wpkh {
+                if flags & VERIFY_WITNESS == 0 {
+                    return Ok(());
+                }
+                // BIP141: the scriptsig must be exactly one push of the
+                // redeem script, nothing before it
+                let mut single_push = vec![redeem_script.len() as u8];
+                single_push.extend(&redeem_script);
+                if hex::decode(&input.scriptsig).ok() != Some(single_push) {
+                    return Err(
+                        "P2SH-P2WPKH scriptsig is not a single push of the redeem script"
+                            .to_string()
+                            .into(),
+                    );
+                }
+                Ok(tx.verify_p2wpkh_input(index, &redeem_script[2..], verifier)?)
+            } else {
+                Err(TxError::Unsupported(
+                    "Unsupported P2SH redeem script".to_string(),
+                ))
+            }
+        }
+        ScriptType::V1P2tr => {
+            if flags & VERIFY_TAPROOT == 0 {
+                return Ok(());
+            }
+            if tx.is_p2tr_script_path(index) {
+                return Err(TxError::Unsupported(
+                    "Taproot script-path spends are not supported".to_string(),
+                ));
+            }
+            Ok(tx.verify_p2tr_input(index, verifier)?)
+        }
+        other => Err(TxError::Unsupported(format!(
+            "Unsupported script type: {}",
+            other.name()
+        ))),
+    }
+}
+
+/// Validates every transaction, verifying all of their inputs in parallel.
+///
+/// The returned results line up with `txs`. A transaction is reported with the
+/// error of its first invalid input, or else of its first unsupported one, so
+/// the outcome does not depend on the order in which workers finish.
+/// Transactions already in the script cache for the same spent outputs and
+/// `flags` skip input verification entirely.
+pub fn validate_transactions(
+    txs: &[Transaction],
+    verifier: &SigVerifier,
+    flags: u32,
+    threads: usize,
+) -> Vec<Result<(), TxError>> {
+    let mut results = txs
+        .iter()
+        .map(|tx| {
+            if tx.is_basic_valid() {
+                Ok(())
+            } else {
+                Err(TxError::Invalid("Failed basic checks".to_string()))
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let cache_keys = parallel_map(txs, threads, |tx| {
+        let wtxid = tx.calculate_wtxid().ok()?;
+        Some((wtxid, spent_outputs_hash(tx)))
+    });
+
+    let mut to_verify = vec![false; txs.len()];
+    for (i, result) in results.iter().enumerate() {
+        if result.is_ok() {
+            to_verify[i] = match &cache_keys[i] {
+                Some((wtxid, spent)) => !verifier.script_cache.contains(wtxid, spent, flags),
+                None => true,
+            };
+        }
+    }
+
+    let jobs = (0..txs.len())
+        .filter(|&i| to_verify[i])
+        .flat_map(|i| (0..txs[i].vin.len()).map(move |j| (i, j)))
+        .collect::<Vec<_>>();
+
+    let input_results = parallel_map(&jobs, threads, |&(i, j)| {
+        verify_input(&txs[i], j, flags, verifier)
+    });
+
+    for (&(i, j), result) in jobs.iter().zip(input_results) {
+        let replace = matches!(
+            (&results[i], &result),
+            (Ok(()), Err(_)) | (Err(TxError::Unsupported(_)), Err(TxError::Invalid(_)))
+        );
+        if replace {
+            results[i] = result.map_err(|e| e.context(&format!("Input {}", j)));
+        }
+    }
+
+    for i in 0..txs.len() {
+        if let (true, Ok(()), Some((wtxid, spent))) = (to_verify[i], &results[i], &cache_keys[i]) {
+            verifier.script_cache.insert(wtxid, spent, flags);
+        }
+    }
+    results
+}
+
+/// SHA256 of the amount and scriptpubkey of every output `tx` spends.
+fn spent_outputs_hash(tx: &Transaction) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for input in &tx.vin {
+        hasher.update(input.prevout.value.to_le_bytes());
+        hasher.update((input.prevout.scriptpubkey.len() as u64).to_le_bytes());
+        hasher.update(input.prevout.scriptpubkey.as_bytes());
+    }
+    hasher.finalize().into()
+}
+
+fn p2sh_redeem_script(
+    tx: &Transaction,
+    index: usize,
+    scriptpubkey: &[u8],
+) -> Result<Vec<u8>, String> {
+    let scriptsig =
+        hex::decode(&tx.vin[index].scriptsig).map_err(|_| "Invalid scriptsig".to_string())?;
+    let redeem_script = parse_pushes(&scriptsig)?
+        .pop()
+        .ok_or("P2SH scriptsig has no redeem script")?;
+    if hash160(&redeem_script) != scriptpubkey[2..22] {
+        return Err("Redeem script hash mismatch".to_string());
+    }
+    Ok(redeem_script)
+}
+
+/// RIPEMD160(SHA256(data)).
+pub fn hash160(data: &[u8]) -> Vec<u8> {
+    Ripemd160::digest(Sha256::digest(data)).to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mempool_tx(file: &str) -> Transaction {
+        let path = format!("{}/mempool/{}.json", env!("CARGO_MANIFEST_DIR"), file);
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn non_minimal_der_signatures_are_rejected() {
+        let verifier = SigVerifier::new(0, 0);
+        let mut tx = mempool_tx("000cb561188c762c81f76976f816829424e2af9e0e491c617b7bf41038df3d35");
+        assert!(verify_input(&tx, 0, VERIFY_ALL, &verifier).is_ok());
+
+        // Pad S (31 bytes) with a zero byte: lax DER still reads the same
+        // signature, strict DER doesn't
+        let witness = tx.vin[0].witness.as_mut().unwrap();
+        let sig = &witness[0];
+        assert_eq!(&sig[..4], "3044");
+        assert_eq!(&sig[74..78], "021f");
+        witness[0] = format!("3045{}0220{}{}", &sig[4..74], "00", &sig[78..]);
+        let result = verify_input(&tx, 0, VERIFY_ALL, &verifier);
+        assert!(matches!(result, Err(TxError::Invalid(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn p2sh_p2wpkh_scriptsig_must_only_push_the_redeem_script() {
+        let verifier = SigVerifier::new(0, 0);
+        let mut tx = mempool_tx("019731eeb5a97dee2f5ee4e3dcfe9fdb27602a64d7a305727b616585197f521a");
+        assert!(verify_input(&tx, 0, VERIFY_ALL, &verifier).is_ok());
+
+        // An extra OP_0 before the redeem script
+        tx.vin[0].scriptsig = format!("00{}", tx.vin[0].scriptsig);
+        let result = verify_input(&tx, 0, VERIFY_ALL, &verifier);
+        assert!(matches!(result, Err(TxError::Invalid(_))), "{:?}", result);
+
+        // The same redeem script through OP_PUSHDATA1
+        tx.vin[0].scriptsig = format!("4c{}", &tx.vin[0].scriptsig[2..]);
+        let result = verify_input(&tx, 0, VERIFY_ALL, &verifier);
+        assert!(matches!(result, Err(TxError::Invalid(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn script_cache_hits_need_the_same_spent_outputs() {
+        let verifier = SigVerifier::new(0, 10);
+        let mut txs = vec![mempool_tx(
+            "000cb561188c762c81f76976f816829424e2af9e0e491c617b7bf41038df3d35",
+        )];
+        assert!(validate_transactions(&txs, &verifier, VERIFY_ALL, 1)[0].is_ok());
+        assert!(validate_transactions(&txs, &verifier, VERIFY_ALL, 1)[0].is_ok());
+        assert_eq!(verifier.script_cache.stats.hits(), 1);
+
+        // Same wtxid, different amount: verified again, and the signature
+        // no longer matches
+        txs[0].vin[0].prevout.value += 1;
+        assert!(validate_transactions(&txs, &verifier, VERIFY_ALL, 1)[0].is_err());
+        assert_eq!(verifier.script_cache.stats.hits(), 1);
+    }
+}
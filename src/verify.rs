@@ -0,0 +1,405 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use crate::block::{calculate_witness_commitment, Block, BlockHeader, WITNESS_COMMITMENT_HEADER};
+use crate::coinbase::{coinbase_height, MAX_COINBASE_SCRIPTSIG_LEN, MIN_COINBASE_SCRIPTSIG_LEN};
+use crate::consensus::{ConsensusParams, MAX_BLOCK_SIGOPS_COST, MAX_BLOCK_WEIGHT};
+use crate::merkle::MerkleTree;
+use crate::network::Network;
+use crate::pow::{bits_to_target, hash_meets_target};
+use crate::template::check_coinbase_value;
+use crate::tx::{serialize_varint, ByteReader, Transaction};
+use crate::validate::{validate_transactions, SigVerifier, VERIFY_ALL};
+
+/// A block read back from disk.
+pub enum BlockFile {
+    /// `output.txt`: the header, the coinbase and the txids in block order.
+    Summary {
+        header: BlockHeader,
+        coinbase: Transaction,
+        txids: Vec<String>,
+    },
+    /// A serialized block with every transaction.
+    Raw(Block),
+}
+
+impl BlockFile {
+    /// Reads `output.txt`, or a raw block as a single hex line or as binary,
+    /// possibly framed as in `blk*.dat`.
+    pub fn read(path: &Path, network: Network) -> Result<BlockFile, String> {
+        let data =
+            fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+
+        let is_hex = data
+            .iter()
+            .all(|b| b.is_ascii_hexdigit() || b.is_ascii_whitespace());
+        if !is_hex {
+            return single_block(parse_raw_blocks(&data, network)?, path);
+        }
+
+        let text = String::from_utf8(data).unwrap();
+        let lines = text.split_whitespace().collect::<Vec<_>>();
+        match lines.as_slice() {
+            [] => Err(format!("{} is empty", path.display())),
+            [raw] => {
+                let bytes = hex::decode(raw).map_err(|_| "Invalid block hex".to_string())?;
+                single_block(parse_raw_blocks(&bytes, network)?, path)
+            }
+            [header, coinbase, txids @ ..] => {
+                let header = hex::decode(header).map_err(|_| "Invalid header hex".to_string())?;
+                if header.len() != 80 {
+                    return Err(format!("Header is {} bytes, expected 80", header.len()));
+                }
+                let header = BlockHeader::parse(&mut ByteReader::new(&header))?;
+
+                let coinbase =
+                    hex::decode(coinbase).map_err(|_| "Invalid coinbase hex".to_string())?;
+                let mut reader = ByteReader::new(&coinbase);
+                let coinbase = Transaction::parse(&mut reader, network)
+                    .map_err(|e| format!("Coinbase: {}", e))?;
+                if !reader.is_empty() {
+                    return Err("Trailing data after the coinbase".to_string());
+                }
+
+                Ok(BlockFile::Summary {
+                    header,
+                    coinbase,
+                    txids: txids.iter().map(|txid| txid.to_string()).collect(),
+                })
+            }
+        }
+    }
+
+    pub fn header_and_txids(&self) -> (&BlockHeader, &[String]) {
+        match self {
+            BlockFile::Summary { header, txids, .. } => (header, txids),
+            BlockFile::Raw(block) => (&block.header, &block.txids),
+        }
+    }
+}
+
+fn single_block(mut blocks: Vec<Block>, path: &Path) -> Result<BlockFile, String> {
+    match blocks.len() {
+        1 => Ok(BlockFile::Raw(blocks.remove(0))),
+        n => Err(format!("{} holds {} blocks, expected 1", path.display(), n)),
+    }
+}
+
+/// Reads every block in a hex or binary file holding one serialized block or
+/// `blk*.dat` framed blocks.
+pub fn read_raw_blocks(path: &Path, network: Network) -> Result<Vec<Block>, String> {
+    let data = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let is_hex = data
+        .iter()
+        .all(|b| b.is_ascii_hexdigit() || b.is_ascii_whitespace());
+    if is_hex {
+        let text = String::from_utf8(data).unwrap();
+        let bytes = hex::decode(text.split_whitespace().collect::<String>())
+            .map_err(|_| "Invalid block hex".to_string())?;
+        return parse_raw_blocks(&bytes, network);
+    }
+    parse_raw_blocks(&data, network)
+}
+
+/// A serialized block, or several if `data` starts with a network magic.
+/// Any network's magic counts, so a file from the wrong network is reported
+/// as such rather than misparsed.
+fn parse_raw_blocks(data: &[u8], network: Network) -> Result<Vec<Block>, String> {
+    let networks = [Network::Mainnet, Network::Testnet, Network::Regtest];
+    if networks.iter().any(|n| data.starts_with(&n.magic())) {
+        Block::parse_blk_file(data, network)
+    } else {
+        Ok(vec![Block::parse(data, network)?])
+    }
+}
+
+/// Outcome of one named block check.
+pub struct Check {
+    pub name: &'static str,
+    pub result: Result<(), String>,
+}
+
+/// Runs every consensus check on a block whose non-coinbase transactions come
+/// from `mempool` (keyed by txid), which also provides their prevouts.
+///
+/// Checks that need the full transactions are skipped when one of them can't
+/// be found, so the result ends with the failing "mempool" check.
+pub fn verify_block(
+    file: &BlockFile,
+    mempool: &HashMap<String, Transaction>,
+    params: &ConsensusParams,
+    verifier: &SigVerifier,
+    threads: usize,
+) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let (header, coinbase, txids) = match file {
+        BlockFile::Summary {
+            header,
+            coinbase,
+            txids,
+        } => (header, coinbase, txids),
+        BlockFile::Raw(block) => match block.transactions.first() {
+            Some(coinbase) => (&block.header, coinbase, &block.txids),
+            None => {
+                checks.push(Check {
+                    name: "coinbase",
+                    result: Err("Block has no transactions".to_string()),
+                });
+                return checks;
+            }
+        },
+    };
+
+    checks.push(Check {
+        name: "proof of work",
+        result: check_proof_of_work(header),
+    });
+    checks.push(Check {
+        name: "merkle root",
+        result: check_merkle_root(header, coinbase, txids),
+    });
+    // Nothing else can be checked without the coinbase's txid
+    if txids.is_empty() {
+        return checks;
+    }
+    let height = coinbase_scriptsig_height(coinbase);
+    checks.push(Check {
+        name: "coinbase",
+        result: height.as_ref().map(|_| ()).map_err(|e| e.clone()),
+    });
+
+    let transactions = match resolve_transactions(file, coinbase, txids, mempool) {
+        Ok(transactions) => transactions,
+        Err(e) => {
+            checks.push(Check {
+                name: "mempool",
+                result: Err(e),
+            });
+            return checks;
+        }
+    };
+    checks.push(Check {
+        name: "mempool",
+        result: Ok(()),
+    });
+
+    checks.push(Check {
+        name: "ordering",
+        result: check_ordering(&transactions, txids),
+    });
+    checks.push(Check {
+        name: "witness commitment",
+        result: check_witness_commitment(&transactions),
+    });
+    checks.push(Check {
+        name: "coinbase value",
+        result: height.and_then(|height| {
+            let fees = transactions[1..].iter().map(|tx| tx.fee()).sum();
+            check_coinbase_value(coinbase, params.block_subsidy(height), fees)
+        }),
+    });
+    checks.push(Check {
+        name: "weight",
+        result: check_weight(&transactions),
+    });
+    checks.push(Check {
+        name: "sigops",
+        result: check_sigops(&transactions),
+    });
+
+    let results = validate_transactions(&transactions[1..], verifier, VERIFY_ALL, threads);
+    checks.push(Check {
+        name: "scripts",
+        result: txids[1..]
+            .iter()
+            .zip(results)
+            .try_for_each(|(txid, result)| result.map_err(|e| format!("{}: {}", txid, e))),
+    });
+    checks
+}
+
+fn check_proof_of_work(header: &BlockHeader) -> Result<(), String> {
+    let target = bits_to_target(header.bits)?;
+    let hash = header.hash();
+    if !hash_meets_target(&hash, &target) {
+        return Err(format!("Hash {} is above the target {:064x}", hash, target));
+    }
+    Ok(())
+}
+
+fn check_merkle_root(
+    header: &BlockHeader,
+    coinbase: &Transaction,
+    txids: &[String],
+) -> Result<(), String> {
+    let coinbase_txid = coinbase.calculate_txid()?;
+    match txids.first() {
+        Some(first) if *first == coinbase_txid => {}
+        Some(first) => {
+            return Err(format!(
+                "First txid {} is not the coinbase txid {}",
+                first, coinbase_txid
+            ))
+        }
+        None => return Err("No txids".to_string()),
+    }
+
+    let tree = MerkleTree::from_ids(txids)?;
+    if tree.is_mutated() {
+        return Err("Merkle tree pairs identical hashes (CVE-2012-2459)".to_string());
+    }
+    let root = tree.root_hex().unwrap();
+    if root != header.merkle_root {
+        return Err(format!(
+            "Header has {} but the txids give {}",
+            header.merkle_root, root
+        ));
+    }
+    Ok(())
+}
+
+/// The first transaction must be the only coinbase, with a BIP34 height.
+fn coinbase_scriptsig_height(coinbase: &Transaction) -> Result<u32, String> {
+    if !coinbase.is_coinbase() {
+        return Err("First transaction is not a coinbase".to_string());
+    }
+    let scriptsig = hex::decode(&coinbase.vin[0].scriptsig)
+        .map_err(|_| "Invalid coinbase scriptsig".to_string())?;
+    if !(MIN_COINBASE_SCRIPTSIG_LEN..=MAX_COINBASE_SCRIPTSIG_LEN).contains(&scriptsig.len()) {
+        return Err(format!(
+            "Coinbase scriptsig is {} bytes, must be {}-{}",
+            scriptsig.len(),
+            MIN_COINBASE_SCRIPTSIG_LEN,
+            MAX_COINBASE_SCRIPTSIG_LEN
+        ));
+    }
+    coinbase_height(&scriptsig)
+}
+
+/// Looks up every non-coinbase transaction in the mempool. For a raw block
+/// the mempool copy must have the same wtxid as the one in the block.
+fn resolve_transactions(
+    file: &BlockFile,
+    coinbase: &Transaction,
+    txids: &[String],
+    mempool: &HashMap<String, Transaction>,
+) -> Result<Vec<Transaction>, String> {
+    let mut transactions = vec![coinbase.clone()];
+    for (i, txid) in txids.iter().enumerate().skip(1) {
+        let tx = mempool
+            .get(txid)
+            .ok_or_else(|| format!("{} is not in the mempool", txid))?;
+        if let BlockFile::Raw(block) = file {
+            if block.transactions[i].calculate_wtxid()? != tx.calculate_wtxid()? {
+                return Err(format!(
+                    "{} has different witness data than the mempool",
+                    txid
+                ));
+            }
+        }
+        transactions.push(tx.clone());
+    }
+    Ok(transactions)
+}
+
+/// Parents must come before children, every spent in-block output must
+/// exist, and no outpoint may be spent twice.
+fn check_ordering(transactions: &[Transaction], txids: &[String]) -> Result<(), String> {
+    let positions = txids
+        .iter()
+        .enumerate()
+        .map(|(i, txid)| (txid.as_str(), i))
+        .collect::<HashMap<_, _>>();
+    if positions.len() != txids.len() {
+        return Err("Duplicate txid in block".to_string());
+    }
+
+    let mut spent = HashSet::new();
+    for (i, tx) in transactions.iter().enumerate().skip(1) {
+        for input in &tx.vin {
+            if let Some(&parent) = positions.get(input.txid.as_str()) {
+                if parent >= i {
+                    return Err(format!(
+                        "{} spends {} which comes later in the block",
+                        txids[i], input.txid
+                    ));
+                }
+                if input.vout as usize >= transactions[parent].vout.len() {
+                    return Err(format!(
+                        "{} spends missing output {}:{}",
+                        txids[i], input.txid, input.vout
+                    ));
+                }
+            }
+            if !spent.insert((input.txid.as_str(), input.vout)) {
+                return Err(format!(
+                    "{}:{} is spent twice (again by {})",
+                    input.txid, input.vout, txids[i]
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// BIP141: the last `aa21a9ed` output commits to the wtxid merkle root and the
+/// coinbase witness reserved value. Without one, no transaction may have a witness.
+fn check_witness_commitment(transactions: &[Transaction]) -> Result<(), String> {
+    let coinbase = &transactions[0];
+    let commitment = coinbase.vout.iter().rev().find_map(|output| {
+        let script = hex::decode(&output.scriptpubkey).ok()?;
+        let is_commitment = script.len() >= 38
+            && script[..2] == [0x6a, 0x24]
+            && script[2..6] == WITNESS_COMMITMENT_HEADER;
+        is_commitment.then(|| hex::encode(&script[6..38]))
+    });
+
+    let Some(commitment) = commitment else {
+        if transactions.iter().any(|tx| tx.has_witness()) {
+            return Err("Block has witness data but no witness commitment".to_string());
+        }
+        return Ok(());
+    };
+
+    let reserved_value = match coinbase.vin[0].witness.as_deref() {
+        Some([item]) => hex::decode(item).ok().filter(|v| v.len() == 32),
+        _ => None,
+    }
+    .ok_or("Coinbase witness must be a single 32 byte reserved value")?;
+
+    let expected = calculate_witness_commitment(transactions, &reserved_value)?;
+    if commitment != expected {
+        return Err(format!(
+            "Coinbase commits to {} but the wtxids give {}",
+            commitment, expected
+        ));
+    }
+    Ok(())
+}
+
+fn check_weight(transactions: &[Transaction]) -> Result<(), String> {
+    let header_and_count = 80 + serialize_varint(transactions.len() as u64).len();
+    let weight = header_and_count * 4 + transactions.iter().map(|tx| tx.weight()).sum::<usize>();
+    if weight > MAX_BLOCK_WEIGHT {
+        return Err(format!(
+            "Block weight {} exceeds {}",
+            weight, MAX_BLOCK_WEIGHT
+        ));
+    }
+    Ok(())
+}
+
+fn check_sigops(transactions: &[Transaction]) -> Result<(), String> {
+    let mut cost = 0;
+    for tx in transactions {
+        cost += tx.sigop_cost()?;
+    }
+    if cost > MAX_BLOCK_SIGOPS_COST {
+        return Err(format!(
+            "Sigop cost {} exceeds {}",
+            cost, MAX_BLOCK_SIGOPS_COST
+        ));
+    }
+    Ok(())
+}